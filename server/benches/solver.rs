@@ -0,0 +1,72 @@
+//! Compares the solver with the pair check that rooms used before it.
+//! Run with `cargo bench`.
+
+use multi::card::{Card, DeckSpec};
+use multi::solver;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::time::Instant;
+
+const BOARDS: usize = 1000;
+const ROUNDS: u32 = 20;
+
+/// Check every pair of cards, without stopping at the first set
+fn has_set_pairs(deck: &DeckSpec, cards: &[Option<Card>]) -> bool {
+    let mut ids = HashSet::new();
+    for card in cards.iter().flatten() {
+        ids.insert(card.id);
+    }
+
+    let mut result = false;
+    for (i, card_i) in cards.iter().enumerate() {
+        if let Some(card_i) = card_i {
+            for card_j in cards.iter().skip(i + 1).flatten() {
+                if ids.contains(&deck.get_third_card(card_i, card_j)) {
+                    result = true;
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn random_boards(deck: &DeckSpec, board_size: usize, rng: &mut StdRng) -> Vec<Vec<Option<Card>>> {
+    (0..BOARDS)
+        .map(|_| {
+            let mut ids: Vec<_> = (0..deck.card_count()).collect();
+            ids.shuffle(rng);
+            ids[..board_size].iter().map(|&id| Some(deck.id_to_card(id))).collect()
+        })
+        .collect()
+}
+
+fn bench<T>(name: &str, boards: &[Vec<Option<Card>>], f: impl Fn(&[Option<Card>]) -> T) {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for board in boards {
+            black_box(f(black_box(board)));
+        }
+    }
+    let per_board = start.elapsed() / (ROUNDS * boards.len() as u32);
+    println!("  {name:<20} {per_board:>10.2?} per board");
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for (deck, board_size) in [(DeckSpec::CLASSIC, 12), (DeckSpec::CLASSIC, 21), (DeckSpec::EXPERT, 21)] {
+        let boards = random_boards(&deck, board_size, &mut rng);
+        for board in boards.iter() {
+            assert_eq!(has_set_pairs(&deck, board), solver::has_set(&deck, board));
+        }
+
+        println!("{} attributes, {board_size} cards", deck.attributes());
+        bench("pair check", &boards, |board| has_set_pairs(&deck, board));
+        bench("has_set", &boards, |board| solver::has_set(&deck, board));
+        bench("count_sets", &boards, |board| solver::count_sets(&deck, board));
+        bench("max_disjoint_sets", &boards, |board| solver::max_disjoint_sets(&deck, board));
+    }
+}
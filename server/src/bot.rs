@@ -0,0 +1,177 @@
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::future::pending;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::card::{Card, DeckSpec};
+use crate::client::{Client, ClientReceiver};
+use crate::messages::{PlayerState, ServerMessage};
+use crate::room_task::{RoomCommand, RoomHandle};
+use crate::solver::{self, Set};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotDifficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl BotDifficulty {
+    /// Time to find a set on a board of 12 cards with only one set (ms)
+    fn base_delay(&self) -> f64 {
+        match self {
+            BotDifficulty::Easy => 15000.0,
+            BotDifficulty::Medium => 8000.0,
+            BotDifficulty::Hard => 4000.0,
+        }
+    }
+
+    /// Chance of picking cards that aren't a set
+    fn mistake_chance(&self) -> f64 {
+        match self {
+            BotDifficulty::Easy => 0.25,
+            BotDifficulty::Medium => 0.05,
+            BotDifficulty::Hard => 0.0,
+        }
+    }
+}
+
+/// Start a bot that plays through the room like any other client. It stops
+/// once the room drops the returned client.
+pub fn spawn(client_id: u32, difficulty: BotDifficulty, room: RoomHandle) -> Client {
+    let (client, receiver) = Client::new(client_id);
+    let bot = Bot {
+        client_id,
+        difficulty,
+        room,
+        rng: StdRng::seed_from_u64(rand::random()),
+        deck: DeckSpec::default(),
+        cards: Vec::new(),
+        taken: Vec::new(),
+        pick_time: None,
+    };
+    tokio::spawn(bot.run(receiver));
+    client
+}
+
+struct Bot {
+    client_id: u32,
+    difficulty: BotDifficulty,
+    room: RoomHandle,
+    rng: StdRng,
+    deck: DeckSpec,
+    cards: Vec<Option<Card>>,
+    /// Cards that were picked correctly, and are about to be replaced
+    taken: Vec<usize>,
+    /// When the bot will pick next
+    pick_time: Option<Instant>,
+}
+
+impl Bot {
+    async fn run(mut self, mut receiver: ClientReceiver) {
+        loop {
+            let pick_time = self.pick_time;
+            let wait = async move {
+                match pick_time {
+                    Some(pick_time) => sleep_until(pick_time).await,
+                    None => pending().await,
+                }
+            };
+
+            tokio::select! {
+                packet = receiver.recv() => match packet {
+                    Some(packet) => self.handle_packet(packet),
+                    None => break,
+                },
+                _ = wait => {
+                    self.pick_time = None;
+                    self.pick().await;
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, packet: ServerMessage) {
+        match packet {
+            ServerMessage::UpdatePlayers { settings, .. } => {
+                self.deck = settings.deck.spec();
+            }
+            ServerMessage::UpdateGame { cards, correct, game_over, start_time, player_state: Some(player_state), .. } => {
+                let cards: Vec<_> = cards.iter().map(|id| id.map(|id| self.deck.id_to_card(id))).collect();
+                let board_changed = cards.iter().map(|card| card.as_ref().map(|card| card.id))
+                    .ne(self.cards.iter().map(|card| card.as_ref().map(|card| card.id)));
+                self.cards = cards;
+                self.taken = correct.iter().flat_map(|pick| pick.cards.iter().copied()).collect();
+
+                if game_over {
+                    self.pick_time = None;
+                } else if board_changed || self.pick_time.is_none() {
+                    self.plan_pick(start_time, &player_state);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Decide when to pick, from how hard the board is
+    fn plan_pick(&mut self, start_time: i32, player_state: &PlayerState) {
+        let sets = self.find_sets();
+        if sets.is_empty() {
+            // Wait for the board to change
+            self.pick_time = None;
+            return;
+        }
+
+        let card_count = self.cards.iter().flatten().count() as f64;
+        let delay = self.difficulty.base_delay() * card_count / 12.0 / (sets.len() as f64).sqrt()
+            * self.rng.random_range(0.7..1.3);
+        let wait = start_time.max(player_state.timeout).max(0) as f64 + delay;
+        self.pick_time = Some(Instant::now() + Duration::from_secs_f64(wait / 1000.0));
+    }
+
+    /// Sets that haven't been picked yet
+    fn find_sets(&self) -> Vec<Set> {
+        solver::find_sets(&self.deck, &self.cards)
+            .into_iter()
+            .filter(|set| !set.iter().any(|card_index| self.taken.contains(card_index)))
+            .collect()
+    }
+
+    async fn pick(&mut self) {
+        let sets = self.find_sets();
+        if sets.is_empty() {
+            return;
+        }
+
+        let mut cards = sets[self.rng.random_range(0..sets.len())].to_vec();
+        if self.rng.random_bool(self.difficulty.mistake_chance()) {
+            if let Some(wrong) = self.find_wrong_cards() {
+                cards = wrong;
+            }
+        }
+
+        self.room.send(RoomCommand::PickCards { client_id: self.client_id, cards }).await;
+    }
+
+    /// Random cards that aren't a set
+    fn find_wrong_cards(&mut self) -> Option<Vec<usize>> {
+        let free: Vec<usize> = (0..self.cards.len())
+            .filter(|&i| self.cards[i].is_some() && !self.taken.contains(&i))
+            .collect();
+        if free.len() < 3 {
+            return None;
+        }
+
+        for _ in 0..10 {
+            let cards: Vec<usize> = index::sample(&mut self.rng, free.len(), 3).iter().map(|i| free[i]).collect();
+            if !self.deck.check_match(&self.cards[cards[0]], &self.cards[cards[1]], &self.cards[cards[2]]) {
+                return Some(cards);
+            }
+        }
+
+        None
+    }
+}
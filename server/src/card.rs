@@ -1,62 +1,185 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::solver;
+
+/// Number of cards swapped when looking for a board with the right number of sets
+const MAX_BOARD_SWAPS: usize = 10_000;
+
 #[derive(Clone, Debug)]
 pub struct Card {
     pub id: i32,
-    pub color_id: i32,
-    pub amount_id: i32,
-    pub shape_id: i32,
-    pub fill_id: i32,
+    /// The value of each attribute, e.g. color, amount, shape, fill
+    pub attributes: Vec<i32>,
 }
 
-pub fn id_to_card(id: i32) -> Card {
-    let color_id = id % 3;
-    let rest = id / 3;
-    let amount_id = rest % 3;
-    let rest = rest / 3;
-    let shape_id = rest % 3;
-    let fill_id = rest / 3;
-
-    Card {
-        id,
-        color_id,
-        amount_id,
-        shape_id,
-        fill_id,
-    }
+/// Describes the cards in a deck. There is one card for every combination of
+/// attribute values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeckSpec {
+    attributes: u32,
+    /// Number of values that each attribute can have
+    values: u32,
 }
 
+impl DeckSpec {
+    /// Color, amount, shape and fill
+    pub const CLASSIC: DeckSpec = DeckSpec { attributes: 4, values: 3 };
+    pub const JUNIOR: DeckSpec = DeckSpec { attributes: 3, values: 3 };
+    pub const EXPERT: DeckSpec = DeckSpec { attributes: 5, values: 3 };
 
-fn get_card_id(color_id: i32, amount_id: i32, shape_id: i32, fill_id: i32) -> i32 {
-    let mut id = fill_id;
-    id = 3 * id + shape_id;
-    id = 3 * id + amount_id;
-    id = 3 * id + color_id;
-    id
-}
+    /// Returns None unless there is at least 1 attribute with at least 3
+    /// values, so that any 2 cards have a third card that makes a set, and
+    /// the card ids fit in an i32.
+    pub fn new(attributes: u32, values: u32) -> Option<DeckSpec> {
+        let valid = attributes > 0 && values >= 3 && (values as i32).checked_pow(attributes).is_some();
+        valid.then_some(DeckSpec { attributes, values })
+    }
 
+    pub fn attributes(&self) -> u32 {
+        self.attributes
+    }
 
-/** Get the third number, that is either the same or different to the other 2 */
-fn get_third_number(num1: i32, num2: i32) -> i32 {
-    if num1 == num2 { num1 } else { 3 - num1 - num2 }
-}
+    pub fn values(&self) -> u32 {
+        self.values
+    }
 
+    pub fn card_count(&self) -> i32 {
+        (self.values as i32).pow(self.attributes)
+    }
 
-/** Get the id of the third card that matches the given 2 cards */
-pub fn get_third_card(card1: &Card, card2: &Card) -> i32 {
-    let color_id = get_third_number(card1.color_id, card2.color_id);
-    let amount_id = get_third_number(card1.amount_id, card2.amount_id);
-    let shape_id = get_third_number(card1.shape_id, card2.shape_id);
-    let fill_id = get_third_number(card1.fill_id, card2.fill_id);
-    let id = get_card_id(color_id, amount_id, shape_id, fill_id);
-    id
+    /// Deal a board with exactly `set_count` sets, by swapping cards with the
+    /// rest of the deck until it has. Returns None if it can't find one.
+    pub fn generate_board(&self, board_size: usize, set_count: usize, rng: &mut impl Rng) -> Option<Vec<Card>> {
+        let mut ids: Vec<_> = (0..self.card_count()).collect();
+        ids.shuffle(rng);
+        if board_size > ids.len() {
+            return None;
+        }
+        let (board_ids, rest_ids) = ids.split_at_mut(board_size);
+        let mut board: Vec<_> = board_ids.iter().map(|&id| Some(self.id_to_card(id))).collect();
+        let distance = |board: &[Option<Card>]| solver::count_sets(self, board).abs_diff(set_count);
+
+        let mut best = distance(&board);
+        for _ in 0..MAX_BOARD_SWAPS {
+            if best == 0 {
+                return Some(board.into_iter().flatten().collect());
+            }
+            if rest_ids.is_empty() {
+                return None;
+            }
+
+            let board_index = rng.random_range(0..board_size);
+            let rest_index = rng.random_range(0..rest_ids.len());
+            std::mem::swap(&mut board_ids[board_index], &mut rest_ids[rest_index]);
+            board[board_index] = Some(self.id_to_card(board_ids[board_index]));
+
+            // Keep swaps that don't take the board further from the target
+            let new_distance = distance(&board);
+            if new_distance <= best {
+                best = new_distance;
+            } else {
+                std::mem::swap(&mut board_ids[board_index], &mut rest_ids[rest_index]);
+                board[board_index] = Some(self.id_to_card(board_ids[board_index]));
+            }
+        }
+
+        None
+    }
+
+    pub fn id_to_card(&self, id: i32) -> Card {
+        let values = self.values as i32;
+        let mut rest = id;
+        let mut attributes = Vec::with_capacity(self.attributes as usize);
+        for _ in 0..self.attributes {
+            attributes.push(rest % values);
+            rest /= values;
+        }
+
+        Card { id, attributes }
+    }
+
+    fn get_card_id(&self, attributes: &[i32]) -> i32 {
+        let mut id = 0;
+        for &value in attributes.iter().rev() {
+            id = self.values as i32 * id + value;
+        }
+        id
+    }
+
+    /** Get the values that are either the same as, or different to, the other 2 */
+    fn get_third_numbers(&self, num1: i32, num2: i32) -> Vec<i32> {
+        if num1 == num2 {
+            vec![num1]
+        } else {
+            (0..self.values as i32).filter(|&num| num != num1 && num != num2).collect()
+        }
+    }
+
+    /** Get the ids of all cards that match the given 2 cards. With 3 values per attribute there is exactly one. */
+    pub fn get_third_cards(&self, card1: &Card, card2: &Card) -> Vec<i32> {
+        let mut partial_cards = vec![Vec::new()];
+        for (&num1, &num2) in card1.attributes.iter().zip(card2.attributes.iter()) {
+            let numbers = self.get_third_numbers(num1, num2);
+            partial_cards = partial_cards
+                .iter()
+                .flat_map(|partial| {
+                    numbers.iter().map(move |&num| {
+                        let mut partial = partial.clone();
+                        partial.push(num);
+                        partial
+                    })
+                })
+                .collect();
+        }
+
+        partial_cards.iter().map(|attributes| self.get_card_id(attributes)).collect()
+    }
+
+    /** Get the id of a third card that matches the given 2 cards */
+    pub fn get_third_card(&self, card1: &Card, card2: &Card) -> i32 {
+        self.get_third_cards(card1, card2)[0]
+    }
+
+    /// Every attribute must be either all the same or all different
+    pub fn check_match(&self, card1: &Option<Card>, card2: &Option<Card>, card3: &Option<Card>) -> bool {
+        match (card1, card2, card3) {
+            (Some(card1), Some(card2), Some(card3)) => {
+                self.get_third_cards(card1, card2).contains(&card3.id)
+            }
+            _ => false
+        }
+    }
 }
 
+impl Default for DeckSpec {
+    fn default() -> Self {
+        DeckSpec::CLASSIC
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_rejects_decks_without_a_third_card() {
+        assert_eq!(DeckSpec::new(4, 2), None);
+        assert_eq!(DeckSpec::new(4, 0), None);
+        assert_eq!(DeckSpec::new(0, 3), None);
+        assert_eq!(DeckSpec::new(40, 3), None);
+        assert_eq!(DeckSpec::new(4, 3), Some(DeckSpec::CLASSIC));
+    }
 
-pub fn check_match(card1: &Option<Card>, card2: &Option<Card>, card3: &Option<Card>) -> bool {
-    match (card1, card2, card3) {
-        (Some(card1), Some(card2), Some(card3)) => {
-            let check_id = get_third_card(card1, card2);
-            check_id == card3.id
+    #[test]
+    fn every_pair_has_a_third_card() {
+        let deck = DeckSpec::new(2, 4).unwrap();
+        for id1 in 0..deck.card_count() {
+            for id2 in 0..deck.card_count() {
+                let (card1, card2) = (deck.id_to_card(id1), deck.id_to_card(id2));
+                let card3 = Some(deck.id_to_card(deck.get_third_card(&card1, &card2)));
+                assert!(deck.check_match(&Some(card1), &Some(card2), &card3));
+            }
         }
-        _ => false
     }
 }
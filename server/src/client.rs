@@ -0,0 +1,133 @@
+use futures_util::stream::SplitSink;
+use futures_util::SinkExt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::messages::ServerMessage;
+
+/// Number of packets that can be waiting to be sent to a client
+const OUTBOUND_QUEUE_SIZE: usize = 32;
+/// Number of packets from `send_packet_wait` that can be waiting
+const WAIT_QUEUE_SIZE: usize = 8;
+
+/// Used to send packets to a connected client. Sending never waits for the
+/// client. Each connection has a writer task that does the actual sending.
+///
+/// If the client is too slow and its queue fills up, game updates are dropped,
+/// keeping only the most recent one to send once there is room. Any other
+/// packet that doesn't fit disconnects the client.
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub id: u32,
+    /// Where the client connected from. Bots don't have one.
+    pub ip: Option<IpAddr>,
+    sender: mpsc::Sender<ServerMessage>,
+    /// Kept apart from `sender`, so that packets that wait can't fill its queue
+    wait_sender: mpsc::Sender<ServerMessage>,
+    /// Newest game update that didn't fit in the queue
+    latest_update: Arc<Mutex<Option<ServerMessage>>>,
+    kick: Arc<Notify>,
+}
+
+impl Client {
+    /// Start the writer task for a new connection
+    pub fn spawn(id: u32, ip: IpAddr, write: SplitSink<WebSocketStream<TcpStream>, Message>) -> Client {
+        let (mut client, receiver) = Client::new(id);
+        client.ip = Some(ip);
+        tokio::spawn(write_packets(write, receiver));
+        client
+    }
+
+    /// A client without a connection. The packets sent to it are read from
+    /// the receiver.
+    pub fn new(id: u32) -> (Client, ClientReceiver) {
+        let (sender, receiver) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let (wait_sender, wait_receiver) = mpsc::channel(WAIT_QUEUE_SIZE);
+        let latest_update = Arc::new(Mutex::new(None));
+        let client = Client {
+            id,
+            ip: None,
+            sender,
+            wait_sender,
+            latest_update: latest_update.clone(),
+            kick: Arc::new(Notify::new()),
+        };
+        (client, ClientReceiver { receiver, wait_receiver, latest_update })
+    }
+
+    pub fn send_packet(&self, data: ServerMessage) {
+        let is_update = matches!(data, ServerMessage::UpdateGame { .. });
+        match self.sender.try_send(data) {
+            Ok(()) => {
+                if is_update {
+                    // Anything waiting is older than this update
+                    self.latest_update.lock().unwrap().take();
+                }
+            }
+            Err(TrySendError::Full(data)) => {
+                if is_update {
+                    println!("[{}] Outbound queue full, holding back game update", self.id);
+                    *self.latest_update.lock().unwrap() = Some(data);
+                } else {
+                    println!("[{}] Outbound queue full, disconnecting", self.id);
+                    self.kick.notify_one();
+                }
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Wait for room instead of dropping the packet. Only for tasks that
+    /// don't send to anybody else. These packets have their own queue, which
+    /// is only sent from when the main queue is empty.
+    pub async fn send_packet_wait(&self, data: ServerMessage) {
+        _ = self.wait_sender.send(data).await;
+    }
+
+    /// Wait until the client should be disconnected for being too slow
+    pub async fn kicked(&self) {
+        self.kick.notified().await;
+    }
+}
+
+/// The packets sent to a client, in order
+#[derive(Debug)]
+pub struct ClientReceiver {
+    receiver: mpsc::Receiver<ServerMessage>,
+    wait_receiver: mpsc::Receiver<ServerMessage>,
+    latest_update: Arc<Mutex<Option<ServerMessage>>>,
+}
+
+impl ClientReceiver {
+    /// Wait for the next packet. Returns None once every `Client` for it is dropped.
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        if self.receiver.is_empty() {
+            // The queue has caught up, so send the update that was held back
+            if let Some(update) = self.latest_update.lock().unwrap().take() {
+                return Some(update);
+            }
+        }
+        tokio::select! {
+            biased;
+            data = self.receiver.recv() => data,
+            Some(data) = self.wait_receiver.recv() => Some(data),
+        }
+    }
+}
+
+async fn write_packets(mut write: SplitSink<WebSocketStream<TcpStream>, Message>, mut receiver: ClientReceiver) {
+    while let Some(data) = receiver.recv().await {
+        let text = serde_json::to_string(&data).unwrap();
+        if let Err(e) = write.send(Message::Text(text.into())).await {
+            eprintln!("WebSocket send error: {e}");
+            return;
+        }
+    }
+
+    _ = write.close().await;
+}
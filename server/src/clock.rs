@@ -0,0 +1,59 @@
+use std::fmt::Debug;
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Instant;
+
+/// Milliseconds since the clock started
+pub type Time = i64;
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Time;
+}
+
+/// Follows the real time, and never goes backwards
+#[derive(Debug)]
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn start() -> MonotonicClock {
+        MonotonicClock { start: Instant::now() }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Time {
+        self.start.elapsed().as_millis() as Time
+    }
+}
+
+/// Only moves when it is told to, so timing can be tested without sleeping
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicI64,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    pub fn advance(&self, ms: Time) {
+        self.now.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Time {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Time from now until the given time (ms), as it is sent to clients
+pub fn relative(time: Time, now: Time) -> i32 {
+    (time - now).clamp(i32::MIN as Time, i32::MAX as Time) as i32
+}
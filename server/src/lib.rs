@@ -0,0 +1,4 @@
+//! Game logic that doesn't depend on the server, so that it can be benchmarked
+
+pub mod card;
+pub mod solver;
@@ -1,19 +1,34 @@
-use futures_util::{SinkExt, StreamExt};
-use serde_json;
+use futures_util::StreamExt;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
-use tokio::time::{Duration, sleep};
+use tokio::task::JoinHandle;
 use tokio_tungstenite::accept_async;
 
-mod card;
+use multi::{card, solver};
+
+mod bot;
+mod client;
+use client::Client;
+mod clock;
+use clock::MonotonicClock;
 mod messages;
-use messages::ClientMessage;
+use messages::{ClientMessage, ErrorCode, ServerMessage};
+mod puzzle;
+use puzzle::PuzzleResults;
+mod replay;
+use replay::ReplayStore;
 mod room;
-use room::{Client, Room};
+use room::{RoomAccess, RoomError};
+mod room_task;
+use room_task::{RoomCommand, RoomHandle};
+mod scoring;
 mod server_state;
 use server_state::ServerState;
+mod settings;
 mod util;
 
 #[tokio::main]
@@ -23,130 +38,55 @@ async fn main() {
     println!("Listening on {addr}");
 
     let state = Arc::new(Mutex::new(ServerState {
-        clients: HashMap::new(),
         next_client_id: 0,
         rooms: HashMap::new(),
+        room_ips: HashMap::new(),
+        clock: Arc::new(MonotonicClock::start()),
+        replays: ReplayStore::new(std::env::var_os("REPLAY_DIR").map(PathBuf::from)),
+        puzzle_results: PuzzleResults::default(),
+        room_summaries: HashMap::new(),
+        room_list_subscribers: HashMap::new(),
     }));
 
-    {
-        let state = state.clone();
-        tokio::spawn(tick(state));
-    }
-
-    while let Ok((stream, _)) = listener.accept().await {
+    while let Ok((stream, addr)) = listener.accept().await {
         let state = state.clone();
-        tokio::spawn(handle_connection(state, stream));
-    }
-}
-
-fn check_delete_room<'a>(state: &'a mut ServerState, room_id: &str) -> Option<&'a Room> {
-    let now = util::get_now();
-    let room = &state.rooms[room_id];
-
-    if let Some(delete_time) = room.delete_time {
-        if now < delete_time {
-            return None;
-        }
-
-        let room_id = room.id.clone();
-        if room.viewers.len() == 0 {
-            state.rooms.remove(&room_id);
-            return None;
-        } else {
-            let mut new_room = Room::new(room_id.clone());
-            for &viewer in room.viewers.iter() {
-                new_room.viewers.push(viewer);
-            }
-            state.rooms.insert(room_id.clone(), new_room);
-            return state.rooms.get(&room_id);
-        }
-    }
-
-    None
-}
-
-async fn tick(state: Arc<Mutex<ServerState>>) {
-    loop {
-        {
-            let mut state = state.lock().await;
-            let now = util::get_now();
-            let room_ids = state.rooms.keys().cloned().collect::<Vec<_>>();
-            for room_id in room_ids {
-                // TODO: See if the room needs deleting
-                //let new_room = check_delete_room(&mut state, room_id);
-                //if let Some(new_room) = new_room {
-                //    send_update_players(&mut state.clients, &mut new_room, true, true);
-                //    continue;
-                //}
-
-                let room = state.rooms.get_mut(&room_id).unwrap();
-                let mut updated = false;
-                let mut i = 0;
-                while i < room.wrong.len() {
-                    if now > room.wrong[i].expire {
-                        room.wrong.remove(i);
-                        updated = true;
-                    } else {
-                        i += 1;
-                    }
-                }
-
-                let mut i = 0;
-                while i < room.correct.len() {
-                    if now > room.correct[i].expire {
-                        for &card_index in &room.correct[i].cards {
-                            room.cards[card_index as usize] = None;
-                        }
-                        room.correct.remove(i);
-                        updated = true;
-                    } else {
-                        i += 1;
-                    }
-                }
-
-                room.add_cards();
-                if updated {
-                    // TODO: send update
-                    //send_update_game_all(room);
-                }
-            }
-        }
-
-        sleep(Duration::from_millis(100)).await;
+        tokio::spawn(handle_connection(state, stream, addr.ip()));
     }
 }
 
-async fn handle_connection(state: Arc<Mutex<ServerState>>, stream: TcpStream) {
+async fn handle_connection(state: Arc<Mutex<ServerState>>, stream: TcpStream, ip: IpAddr) {
     let ws_stream = accept_async(stream).await.expect("Error during the websocket handshake");
     let (write, mut read) = ws_stream.split();
 
     println!("New WebSocket connection");
 
-    let client_id = {
-        let mut state = state.lock().await;
-        let client = Client {
-            id: state.next_client_id,
-            room_id: None,
-            name: None,
-            sender: write,
+    let client_id = state.lock().await.new_client_id();
+    let client = Client::spawn(client_id, ip, write);
+    let mut connection = Connection { ip, room: None, replay_task: None };
+
+    loop {
+        let raw_result = tokio::select! {
+            raw_result = read.next() => match raw_result {
+                Some(raw_result) => raw_result,
+                None => break,
+            },
+            _ = client.kicked() => {
+                println!("[{client_id}] Client is too slow");
+                break;
+            }
         };
-        state.clients.insert(client.id, client);
-        state.next_client_id += 1;
-        state.next_client_id - 1
-    };
 
-    while let Some(raw_result) = read.next().await {
         match raw_result {
             Ok(raw_message) => {
                 if let Ok(raw_message) = raw_message.to_text() {
-                    if raw_message == "" {
+                    if raw_message.is_empty() {
                         println!("Empty message");
                         break;
                     }
 
                     match serde_json::from_str::<ClientMessage>(raw_message) {
                         Ok(message) => {
-                            handle_message(&state, message, client_id).await;
+                            handle_message(&state, &client, &mut connection, message).await;
                         }
                         Err(e) => {
                             eprintln!("Error {e}");
@@ -162,44 +102,182 @@ async fn handle_connection(state: Arc<Mutex<ServerState>>, stream: TcpStream) {
         }
     }
 
+    // The writer task closes the connection once the room has dropped the client
     println!("Client disconnected");
-    let mut client = {
-        let mut state = state.lock().await;
-        state.disconnect(client_id).await;
-        state.clients.remove(&client_id).unwrap()
-    };
-    _ = client.sender.close().await;
+    if let Some(room) = connection.room {
+        room.send(RoomCommand::Disconnect { client_id }).await;
+    }
+    if let Some(replay_task) = connection.replay_task {
+        replay_task.abort();
+    }
+    state.lock().await.room_list_subscribers.remove(&client_id);
 }
 
-async fn handle_message(state: &Arc<Mutex<ServerState>>, message: ClientMessage, client_id: u32) {
-    let mut state = state.lock().await;
+/// What a connected client is doing
+struct Connection {
+    /// Where the client connected from
+    ip: IpAddr,
+    /// The room that the client is viewing or playing in
+    room: Option<RoomHandle>,
+    /// Sending a replay to the client
+    replay_task: Option<JoinHandle<()>>,
+}
+
+async fn handle_message(state: &Arc<Mutex<ServerState>>, client: &Client, connection: &mut Connection, message: ClientMessage) {
+    let client_id = client.id;
+    let room = &mut connection.room;
 
-    match message {
-        ClientMessage::ViewRoom { id } => {
+    let command = match message {
+        ClientMessage::ViewRoom { id, password, invite_code } => {
             println!("[{client_id}] ViewRoom {id}");
-            state.view_room(client_id, &id).await;
+            if let Some(old_room) = room.take() {
+                old_room.send(RoomCommand::Disconnect { client_id }).await;
+            }
+            let new_room = loop {
+                let result = state.lock().await.get_room(&id, connection.ip, state);
+                let new_room = match result {
+                    Ok(new_room) => new_room,
+                    Err(reason) => return client.send_packet(reason.into()),
+                };
+
+                // Wait for space without the lock, as the room locks the state too.
+                // Then send with the lock, so the room can't be deleted in between.
+                let permit = new_room.reserve().await;
+                let mut locked_state = state.lock().await;
+                let is_running = locked_state.rooms.get(&id).is_some_and(|room| room.is_same_room(&new_room));
+                match permit {
+                    Some(permit) if is_running => {
+                        permit.send(RoomCommand::View { client: client.clone(), password, invite_code });
+                        break new_room.clone();
+                    }
+                    // The task stopped without removing the room
+                    None if is_running => locked_state.remove_room(&id),
+                    // The room was deleted while waiting, so look it up again
+                    _ => {}
+                }
+            };
+            *room = Some(new_room);
+            return;
+        }
+        ClientMessage::CreateRoom { password, invite } => {
+            if invite && password.is_some() {
+                let error = RoomError::new(ErrorCode::InvalidSettings, "A room can have a password or an invite code, not both");
+                return client.send_packet(error.into());
+            }
+            let invite_code = invite.then(util::new_token);
+            let access = match (&invite_code, password) {
+                (Some(invite_code), _) => RoomAccess::Invite(invite_code.clone()),
+                (None, Some(password)) => RoomAccess::Password(password),
+                (None, None) => RoomAccess::Public,
+            };
+            let result = state.lock().await.create_room(access, connection.ip, state);
+            match result {
+                Ok(id) => {
+                    println!("[{client_id}] CreateRoom {id}");
+                    client.send_packet(ServerMessage::RoomCreated { id, invite_code });
+                }
+                Err(reason) => client.send_packet(reason.into()),
+            }
+            return;
         }
-        ClientMessage::JoinRoom { name } => {
+        ClientMessage::JoinRoom { name, token } => {
             println!("[{client_id}] JoinRoom {name}");
-            state.join_room(client_id, name).await;
+            RoomCommand::Join { client_id, name, token }
         }
         ClientMessage::LeaveRoom {} => {
             println!("[{client_id}] Client left the room");
-            state.leave_view_room(client_id).await;
+            RoomCommand::Leave { client_id }
         }
         ClientMessage::PickCards { cards } => {
             println!("[{client_id}] Client picked cards: {cards:?}");
-            state.pick_cards(client_id, &cards).await;
+            RoomCommand::PickCards { client_id, cards }
+        }
+        ClientMessage::UpdateSettings { settings } => {
+            println!("[{client_id}] Settings update requested: {settings:?}");
+            RoomCommand::UpdateSettings { client_id, settings }
         }
         ClientMessage::StartGame {} => {
             println!("[{client_id}] Game start requested");
-            state.start_game(client_id).await;
+            RoomCommand::StartGame { client_id }
+        }
+        ClientMessage::RequestHint {} => {
+            println!("[{client_id}] Hint requested");
+            RoomCommand::RequestHint { client_id }
+        }
+        ClientMessage::ChooseTeam { team } => {
+            println!("[{client_id}] Team {team} chosen");
+            RoomCommand::ChooseTeam { client_id, team }
+        }
+        ClientMessage::KickPlayer { player_index } => {
+            println!("[{client_id}] Kick player {player_index}");
+            RoomCommand::KickPlayer { client_id, player_index }
+        }
+        ClientMessage::TransferHost { player_index } => {
+            println!("[{client_id}] Transfer host to player {player_index}");
+            RoomCommand::TransferHost { client_id, player_index }
+        }
+        ClientMessage::LockLobby { locked } => {
+            println!("[{client_id}] Lock lobby: {locked}");
+            RoomCommand::LockLobby { client_id, locked }
+        }
+        ClientMessage::Rematch {} => {
+            println!("[{client_id}] Rematch vote");
+            RoomCommand::Rematch { client_id }
+        }
+        ClientMessage::AddBot { difficulty } => {
+            println!("[{client_id}] Bot requested: {difficulty:?}");
+            let Some(room) = room else {
+                return client.send_packet(RoomError::new(ErrorCode::NotInRoom, "Not in a room").into());
+            };
+            let bot_id = state.lock().await.new_client_id();
+            let bot = bot::spawn(bot_id, difficulty, room.clone());
+            RoomCommand::AddBot { client_id, bot, difficulty }
+        }
+        ClientMessage::WatchReplay { id, speed } => {
+            println!("[{client_id}] WatchReplay {id} at {speed}x");
+            if let Some(replay_task) = connection.replay_task.take() {
+                replay_task.abort();
+            }
+            match replay::find_replay(state, &id).await {
+                Some(replay) => {
+                    let speed = if speed.is_finite() { speed.clamp(0.1, 100.0) } else { 1.0 };
+                    connection.replay_task = Some(tokio::spawn(replay::stream_replay(client.clone(), replay, speed)));
+                }
+                None => client.send_packet(RoomError::new(ErrorCode::ReplayNotFound, format!("There is no replay {id}")).into()),
+            }
+            return;
+        }
+        ClientMessage::ListRooms { subscribe } => {
+            let packet = {
+                let mut state = state.lock().await;
+                if subscribe {
+                    state.room_list_subscribers.insert(client_id, client.clone());
+                } else {
+                    state.room_list_subscribers.remove(&client_id);
+                }
+                state.get_room_list_packet()
+            };
+            client.send_packet(packet);
+            return;
+        }
+        ClientMessage::GetPuzzleResults { date } => {
+            let date = date.unwrap_or_else(|| util::format_day(util::today()));
+            let results = state.lock().await.puzzle_results.get(&date);
+            client.send_packet(ServerMessage::PuzzleResults { date, results });
+            return;
         }
         ClientMessage::Heartbeat {} => {
             // Used to keep the connection alive
+            return;
         }
         ClientMessage::Unknown => {
             println!("[{client_id}] Unknown message received");
+            return;
         }
+    };
+
+    match room {
+        Some(room) => room.send(command).await,
+        None => client.send_packet(RoomError::new(ErrorCode::NotInRoom, "Not in a room").into()),
     }
 }
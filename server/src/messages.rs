@@ -1,13 +1,41 @@
 use serde::{Serialize, Deserialize};
 
+use crate::bot::BotDifficulty;
+use crate::puzzle::PuzzleResult;
+use crate::replay::ReplayEntry;
+use crate::settings::RoomSettings;
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
     #[serde(rename = "view-room")]
-    ViewRoom { id: String },
+    ViewRoom {
+        id: String,
+        /// Needed for rooms created with a password
+        #[serde(default)]
+        password: Option<String>,
+        /// Needed for rooms created with an invite code
+        #[serde(default)]
+        invite_code: Option<String>,
+    },
+
+    /// Create a room with a new id, which can be kept private with a password
+    /// or a generated invite code, but not both. Answered with `RoomCreated`.
+    #[serde(rename = "create-room")]
+    CreateRoom {
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        invite: bool,
+    },
 
     #[serde(rename = "join-room")]
-    JoinRoom { name: String },
+    JoinRoom {
+        name: String,
+        /// From `Joined`, to take back a seat after disconnecting
+        #[serde(default)]
+        token: Option<String>,
+    },
 
     #[serde(rename = "leave-room")]
     LeaveRoom {},
@@ -15,9 +43,64 @@ pub enum ClientMessage {
     #[serde(rename = "pick-cards")]
     PickCards { cards: Vec<usize> },
 
+    #[serde(rename = "update-settings")]
+    UpdateSettings { settings: RoomSettings },
+
     #[serde(rename = "start-game")]
     StartGame {},
 
+    /// Reveal a card of a set on the board. Asking again reveals a second card.
+    #[serde(rename = "request-hint")]
+    RequestHint {},
+
+    /// Move to another team, before the game starts
+    #[serde(rename = "choose-team")]
+    ChooseTeam { team: u32 },
+
+    /// Only for the host. Kicked players can't take back their seat.
+    #[serde(rename = "kick-player")]
+    KickPlayer { player_index: u32 },
+
+    /// Only for the host
+    #[serde(rename = "transfer-host")]
+    TransferHost { player_index: u32 },
+
+    /// Only for the host. Nobody new can join a locked lobby.
+    #[serde(rename = "lock-lobby")]
+    LockLobby { locked: bool },
+
+    /// Vote to play again once the game is over. It starts when every
+    /// connected player, or the host, has voted.
+    #[serde(rename = "rematch")]
+    Rematch {},
+
+    /// Add a bot player to the room, before the game starts
+    #[serde(rename = "add-bot")]
+    AddBot { difficulty: BotDifficulty },
+
+    /// Stream a finished game. `speed` of 2 plays it twice as fast.
+    #[serde(rename = "watch-replay")]
+    WatchReplay {
+        id: String,
+        #[serde(default = "default_replay_speed")]
+        speed: f64,
+    },
+
+    /// List the public rooms. With `subscribe`, the list is sent again
+    /// whenever it changes, until this is sent without it.
+    #[serde(rename = "list-rooms")]
+    ListRooms {
+        #[serde(default)]
+        subscribe: bool,
+    },
+
+    /// Results of the daily puzzle. Today's if no date is given.
+    #[serde(rename = "get-puzzle-results")]
+    GetPuzzleResults {
+        #[serde(default)]
+        date: Option<String>,
+    },
+
     #[serde(rename = "heartbeat")]
     Heartbeat {},
 
@@ -25,12 +108,26 @@ pub enum ClientMessage {
     Unknown,
 }
 
+fn default_replay_speed() -> f64 {
+    1.0
+}
+
 /// This packet is only sent if the game has a start time
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
+    /// Sent when the client can't view or join the room
     #[serde(rename = "reject-join-game")]
-    RejectJoinGame {},
+    RejectJoinGame { reason: RejectReason, message: String },
+
+    /// The invite code is only set if it was asked for
+    #[serde(rename = "room-created")]
+    RoomCreated { id: String, invite_code: Option<String> },
+
+    /// Sent to a client when it joins as a player. The token is needed to
+    /// rejoin a started game after disconnecting.
+    #[serde(rename = "joined")]
+    Joined { token: String, player_index: u32 },
 
     #[serde(rename = "update-game")]
     UpdateGame {
@@ -40,22 +137,184 @@ pub enum ServerMessage {
         correct: Vec<PickCards>,
         game_over: bool,
         start_time: i32,
+        /// Only sent once the game is over, as it gives away the cards to come
+        seed: Option<u32>,
+        /// Only sent to players, about themselves
+        player_state: Option<PlayerState>,
+        /// Sets found so far in a puzzle
+        found: Vec<Vec<usize>>,
+        /// Player indexes, from first place to last
+        rankings: Vec<u32>,
+        /// Totals for each team, if there are teams
+        teams: Vec<TeamUpdate>,
     },
 
     #[serde(rename = "update-players")]
-    UpdatePlayers { players: Vec<PlayerUpdate>, started: bool },
+    UpdatePlayers { players: Vec<PlayerUpdate>, started: bool, settings: RoomSettings, locked: bool },
+
+    /// The host removed this client from the players. It is still viewing the
+    /// room, but nobody from its address can join it again.
+    #[serde(rename = "kicked")]
+    Kicked {},
+
+    /// The game is over, and can be watched again with `WatchReplay`
+    #[serde(rename = "replay-saved")]
+    ReplaySaved { id: String },
+
+    #[serde(rename = "replay-event")]
+    ReplayEvent { id: String, entry: ReplayEntry },
+
+    /// Sent after the last event of a replay
+    #[serde(rename = "replay-end")]
+    ReplayEnd { id: String },
+
+    /// Sent to the player at the end of a practice game
+    #[serde(rename = "practice-summary")]
+    PracticeSummary {
+        /// Time taken to find each set (ms)
+        set_times: Vec<i32>,
+        /// Time from the cards being shown until the game was over (ms)
+        total_time: i32,
+        mistakes: i32,
+        hints: u32,
+    },
+
+    #[serde(rename = "room-list")]
+    RoomList { rooms: Vec<RoomSummary> },
+
+    /// Fastest first. Sent when a puzzle is finished, or when asked for.
+    #[serde(rename = "puzzle-results")]
+    PuzzleResults { date: String, results: Vec<PuzzleResult> },
+
+    /// A request from the client was rejected
+    #[serde(rename = "error")]
+    Error { code: ErrorCode, message: String },
 
     #[serde(other)]
     Unknown,
 }
 
+/// A public room, as shown in the room list
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RoomSummary {
+    pub id: String,
+    /// Connected players, not counting bots
+    pub players: u32,
+    pub bots: u32,
+    pub spectators: u32,
+    pub started: bool,
+    pub game_over: bool,
+    pub locked: bool,
+    pub settings: RoomSettings,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RejectReason {
+    /// The password or invite code of a private room is missing or wrong
+    WrongPassword,
+    /// The client has to view the room before joining it
+    NotViewing,
+    /// The client already has a seat in the room
+    AlreadyJoined,
+    /// The game has started, and the client doesn't have a seat's token
+    Started,
+    RoomFull,
+    TooManySpectators,
+    Locked,
+    Kicked,
+    NameTaken,
+    /// This address already has too many rooms open, so no new room was made
+    TooManyRooms,
+}
+
+impl RejectReason {
+    pub fn message(&self) -> &'static str {
+        match self {
+            RejectReason::WrongPassword => "The password or invite code is wrong",
+            RejectReason::NotViewing => "View the room before joining it",
+            RejectReason::AlreadyJoined => "You are already playing in this room",
+            RejectReason::Started => "The game has already started",
+            RejectReason::RoomFull => "The room is full",
+            RejectReason::TooManySpectators => "The room has too many spectators",
+            RejectReason::Locked => "The host has locked the room",
+            RejectReason::Kicked => "The host removed you from the players",
+            RejectReason::NameTaken => "Somebody in the room already has that name",
+            RejectReason::TooManyRooms => "You have too many rooms open",
+        }
+    }
+}
+
+impl From<RejectReason> for ServerMessage {
+    fn from(reason: RejectReason) -> Self {
+        ServerMessage::RejectJoinGame { reason, message: reason.message().to_string() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    NotInRoom,
+    NotPlayer,
+    NotStarted,
+    GameOver,
+    InvalidPick,
+    EmptySlot,
+    CardsTaken,
+    TimedOut,
+    InvalidSettings,
+    ReplayNotFound,
+    HintsDisabled,
+    NoHint,
+    AlreadyStarted,
+    RoomFull,
+    NotHost,
+    NotOver,
+    RematchDisabled,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PlayerUpdate {
     pub name: String,
+    /// Number of sets found
     pub score: i32,
+    /// Number of wrong picks
     pub minus_score: i32,
+    /// From the room's scoring rule, which decides the rankings
+    pub points: i32,
+    /// Points over every game played in the room, including this one
+    pub series_points: i32,
+    /// Voted for a rematch
+    pub rematch: bool,
     pub timeout: i32,
     pub connected: bool,
+    /// Number of cards revealed by hints
+    pub hints: u32,
+    /// Only set for bots
+    pub bot: Option<BotDifficulty>,
+    /// Only set if the room has teams
+    pub team: Option<u32>,
+    pub host: bool,
+}
+
+/// The scores of the players in a team, added up
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TeamUpdate {
+    pub score: i32,
+    pub minus_score: i32,
+    pub points: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlayerState {
+    pub player_index: u32,
+    /// Time until the player can pick again (ms)
+    pub timeout: i32,
+    /// The player's picks that are still highlighted
+    pub picks: Vec<PickCards>,
+    pub can_pick: bool,
+    /// Cards of a set, revealed by hints
+    pub hint: Vec<usize>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Number of cards on a puzzle board
+pub const PUZZLE_BOARD_SIZE: usize = 12;
+/// Number of sets on a puzzle board, which all have to be found
+pub const PUZZLE_SETS: usize = 6;
+
+/// How a player did in a daily puzzle
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PuzzleResult {
+    pub name: String,
+    /// Time taken to find every set (ms)
+    pub time: i32,
+    pub mistakes: i32,
+    pub hints: u32,
+}
+
+/// Results of the daily puzzles, by date
+#[derive(Debug, Default)]
+pub struct PuzzleResults {
+    days: HashMap<String, Vec<PuzzleResult>>,
+}
+
+impl PuzzleResults {
+    /// Results are kept with the fastest first. Only the first result for
+    /// each name counts, as the board is the same all day. Returns whether
+    /// the result was kept.
+    pub fn insert(&mut self, date: &str, result: PuzzleResult) -> bool {
+        let results = self.days.entry(date.to_string()).or_default();
+        if results.iter().any(|other| other.name == result.name) {
+            return false;
+        }
+        let index = results.partition_point(|other| (other.time, other.mistakes) <= (result.time, result.mistakes));
+        results.insert(index, result);
+        true
+    }
+
+    pub fn get(&self, date: &str) -> Vec<PuzzleResult> {
+        self.days.get(date).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &str, time: i32, mistakes: i32) -> PuzzleResult {
+        PuzzleResult { name: name.to_string(), time, mistakes, hints: 0 }
+    }
+
+    #[test]
+    fn keeps_the_first_result_for_each_name() {
+        let mut results = PuzzleResults::default();
+        assert!(results.insert("2026-01-01", result("a", 5000, 0)));
+        assert!(results.insert("2026-01-01", result("b", 3000, 1)));
+        assert!(results.insert("2026-01-01", result("c", 3000, 0)));
+        // Knowing the board makes the second try faster
+        assert!(!results.insert("2026-01-01", result("a", 100, 0)));
+        assert!(results.insert("2026-01-02", result("a", 100, 0)));
+
+        let names: Vec<_> = results.get("2026-01-01").into_iter().map(|result| result.name).collect();
+        assert_eq!(names, ["c", "b", "a"]);
+        assert_eq!(results.get("2026-01-02").len(), 1);
+    }
+}
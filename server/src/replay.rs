@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use crate::client::Client;
+use crate::clock::Time;
+use crate::messages::ServerMessage;
+use crate::server_state::ServerState;
+use crate::settings::RoomSettings;
+
+/// Number of replays kept in memory. Older ones can still be loaded from disk.
+const MAX_REPLAYS: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PickOutcome {
+    Correct,
+    Wrong,
+    /// Somebody else already picked one of the cards
+    Taken,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ReplayEvent {
+    Start { room_id: String, settings: RoomSettings, seed: u32, players: Vec<String> },
+    /// The first cards on the board
+    Deal { cards: Vec<Option<i32>> },
+    /// The board after refilling the spaces left by correct picks
+    Refill { cards: Vec<Option<i32>> },
+    Pick { player: u32, cards: Vec<usize>, outcome: PickOutcome },
+    /// Cards revealed to a player by a hint
+    Hint { player: u32, cards: Vec<usize> },
+    /// A player took back their seat
+    Join { player: u32 },
+    Leave { player: u32 },
+    GameOver {},
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReplayEntry {
+    /// Time since the game was started (ms)
+    pub time: Time,
+    pub event: ReplayEvent,
+}
+
+/// Everything that happened in a game, in order
+#[derive(Clone, Debug)]
+pub struct Replay {
+    pub id: String,
+    /// When the game was started
+    pub start: Time,
+    pub entries: Vec<ReplayEntry>,
+}
+
+impl Replay {
+    pub fn new(id: String, start: Time) -> Replay {
+        Replay { id, start, entries: Vec::new() }
+    }
+
+    pub fn record(&mut self, now: Time, event: ReplayEvent) {
+        self.entries.push(ReplayEntry { time: now - self.start, event });
+    }
+
+    /// One entry per line
+    pub fn to_json_lines(&self) -> String {
+        let mut text = String::new();
+        for entry in self.entries.iter() {
+            text += &serde_json::to_string(entry).unwrap();
+            text += "\n";
+        }
+        text
+    }
+
+    pub fn from_json_lines(id: String, text: &str) -> serde_json::Result<Replay> {
+        let entries = text
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?;
+        Ok(Replay { id, start: 0, entries })
+    }
+}
+
+/// Finished replays. The most recent ones are kept in memory, and all of them
+/// are saved to `dir` if it is set.
+#[derive(Debug, Default)]
+pub struct ReplayStore {
+    replays: HashMap<String, Arc<Replay>>,
+    /// Oldest first
+    order: VecDeque<String>,
+    pub dir: Option<PathBuf>,
+}
+
+impl ReplayStore {
+    pub fn new(dir: Option<PathBuf>) -> ReplayStore {
+        ReplayStore { dir, ..Default::default() }
+    }
+
+    pub fn insert(&mut self, replay: Replay) {
+        if let Some(dir) = self.dir.clone() {
+            let path = dir.join(format!("{}.jsonl", replay.id));
+            let text = replay.to_json_lines();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = std::fs::write(&path, text) {
+                    eprintln!("Failed to save replay {}: {e}", path.display());
+                }
+            });
+        }
+
+        if self.order.len() >= MAX_REPLAYS {
+            if let Some(old_id) = self.order.pop_front() {
+                self.replays.remove(&old_id);
+            }
+        }
+        self.order.push_back(replay.id.clone());
+        self.replays.insert(replay.id.clone(), Arc::new(replay));
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Replay>> {
+        self.replays.get(id).cloned()
+    }
+}
+
+fn load_replay(dir: &Path, id: &str) -> Option<Replay> {
+    // Ids are tokens, so this can't escape the directory
+    if !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let text = std::fs::read_to_string(dir.join(format!("{id}.jsonl"))).ok()?;
+    Replay::from_json_lines(id.to_string(), &text).ok()
+}
+
+/// Get a replay from memory, or from disk if it is no longer in memory
+pub async fn find_replay(state: &Arc<Mutex<ServerState>>, id: &str) -> Option<Arc<Replay>> {
+    let dir = {
+        let state = state.lock().await;
+        if let Some(replay) = state.replays.get(id) {
+            return Some(replay);
+        }
+        state.replays.dir.clone()?
+    };
+
+    let id = id.to_string();
+    let replay = tokio::task::spawn_blocking(move || load_replay(&dir, &id)).await.ok()??;
+    Some(Arc::new(replay))
+}
+
+/// Send the replay to the client, keeping the original timing, sped up by `speed`
+pub async fn stream_replay(client: Client, replay: Arc<Replay>, speed: f64) {
+    let mut last_time = 0;
+    for entry in replay.entries.iter() {
+        let wait = (entry.time - last_time).max(0) as f64 / speed;
+        sleep(Duration::from_secs_f64(wait / 1000.0)).await;
+        last_time = entry.time;
+
+        client.send_packet_wait(ServerMessage::ReplayEvent { id: replay.id.clone(), entry: entry.clone() }).await;
+    }
+
+    client.send_packet_wait(ServerMessage::ReplayEnd { id: replay.id.clone() }).await;
+}
@@ -1,70 +1,210 @@
-use futures_util::stream::SplitSink;
-use rand;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use std::cmp::{max,min};
-use std::collections::HashSet;
-use std::iter;
-use tokio::net::TcpStream;
-use tokio_tungstenite::WebSocketStream;
-use tokio_tungstenite::tungstenite::Message;
+use std::net::IpAddr;
+use std::sync::Arc;
 
-use crate::card::{check_match, get_third_card, id_to_card, Card};
-use crate::messages::{PickCards, PlayerUpdate, ServerMessage};
-use crate::util::{self, get_now};
+use crate::bot::BotDifficulty;
+use crate::card::{Card, DeckSpec};
+use crate::clock::{relative, Clock, Time};
+use crate::messages::{ErrorCode, PickCards, PlayerState, PlayerUpdate, RoomSummary, ServerMessage, TeamUpdate};
+use crate::puzzle::{PuzzleResult, PUZZLE_BOARD_SIZE, PUZZLE_SETS};
+use crate::replay::{PickOutcome, Replay, ReplayEvent};
+use crate::settings::{DeckVariant, GameMode, RoomSettings};
+use crate::solver;
+use crate::util;
 
-const COLOR_EXPIRE_MS: i32 = 5000;
-const TIMEOUT_MS: i32 = 10000;
+/// Why a request from a client was rejected
+#[derive(Clone, Debug)]
+pub struct RoomError {
+    pub code: ErrorCode,
+    pub message: String,
+}
 
-#[derive(Debug)]
-pub struct Client {
-    pub id: u32,
-    pub room_id: Option<String>,
-    pub name: Option<String>,
-    pub sender: SplitSink<WebSocketStream<TcpStream>, Message>,
+impl RoomError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> RoomError {
+        RoomError { code, message: message.into() }
+    }
+}
+
+impl From<RoomError> for ServerMessage {
+    fn from(error: RoomError) -> Self {
+        ServerMessage::Error { code: error.code, message: error.message }
+    }
+}
+
+/// Who can view and join a room
+#[derive(Clone, Debug, Default)]
+pub enum RoomAccess {
+    /// Anybody who knows the room id
+    #[default]
+    Public,
+    Password(String),
+    /// Only clients that were given the generated code
+    Invite(String),
+}
+
+impl RoomAccess {
+    pub fn allows(&self, password: Option<&str>, invite_code: Option<&str>) -> bool {
+        match self {
+            RoomAccess::Public => true,
+            RoomAccess::Password(expected) => password == Some(expected.as_str()),
+            RoomAccess::Invite(expected) => invite_code == Some(expected.as_str()),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Player {
     pub client_id: Option<u32>,
     pub name: String,
+    /// Secret that the client can use to reconnect
+    pub token: String,
+    /// Number of sets found
     pub score: i32,
+    /// Number of wrong picks
     pub minus_score: i32,
-    pub timeout: i32,
+    /// From the room's scoring rule
+    pub points: i32,
+    /// Sets found since the last wrong pick
+    pub streak: u32,
+    /// When the player can pick again
+    pub timeout: Time,
+    /// Number of cards revealed by hints, over the whole game
+    pub hints: u32,
+    /// Cards of a set that hints have revealed, until the board changes
+    pub hint: Vec<usize>,
+    /// Set if the player is played by the server
+    pub bot: Option<BotDifficulty>,
+    /// Only set if the room has teams
+    pub team: Option<u32>,
+    /// Can start the game, and manage the other players
+    pub host: bool,
+    /// Points from the earlier games in the room
+    pub series_points: i32,
+    /// Voted for a rematch
+    pub rematch: bool,
+}
+
+impl Player {
+    pub fn new(client_id: u32, name: String) -> Player {
+        Player {
+            client_id: Some(client_id),
+            name,
+            token: util::new_token(),
+            score: 0,
+            minus_score: 0,
+            points: 0,
+            streak: 0,
+            timeout: 0,
+            hints: 0,
+            hint: Vec::new(),
+            bot: None,
+            team: None,
+            host: false,
+            series_points: 0,
+            rematch: false,
+        }
+    }
+}
+
+/// Cards that a player picked, which stay highlighted until they expire
+#[derive(Clone, Debug)]
+pub struct Pick {
+    pub player: u32,
+    pub cards: Vec<usize>,
+    pub expire: Time,
+}
+
+impl Pick {
+    pub fn to_update(&self, now: Time) -> PickCards {
+        PickCards {
+            player: self.player,
+            cards: self.cards.clone(),
+            expire: relative(self.expire, now),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Room {
     pub id: String,
+    pub access: RoomAccess,
     pub viewers: Vec<u32>,
     pub players: Vec<Player>,
     pub started: bool,
-    pub start_time: i32,
+    /// Nobody new can join the lobby
+    pub locked: bool,
+    /// Addresses of the clients that the host kicked, which can't join again
+    pub kicked: Vec<IpAddr>,
+    pub settings: RoomSettings,
+    /// The deck being played, from the settings when the game started
+    pub deck: DeckSpec,
+    pub start_time: Time,
+    /// Seed for `rng`, so that the game can be reproduced
+    pub seed: u32,
+    /// Used for everything random in the game
+    pub rng: StdRng,
     pub cards_left: Vec<Card>,
     pub cards: Vec<Option<Card>>,
     /// Picked cards that are correct
-    pub correct: Vec<PickCards>,
+    pub correct: Vec<Pick>,
     /// Picked cards that are wrong
-    pub wrong: Vec<PickCards>,
+    pub wrong: Vec<Pick>,
     pub game_over: bool,
+    /// When the cards on the board last changed
+    pub board_time: Time,
+    /// When the last set was found, or the board changed if that was later
+    pub last_found: Time,
+    /// When the last set was found
+    pub last_set_time: Time,
+    /// Time taken to find each set, in order
+    pub set_times: Vec<Time>,
+    /// Date of the puzzle being played
+    pub date: String,
+    /// Sets found in a puzzle, with the cards in increasing order
+    pub found: Vec<Vec<usize>>,
+    /// Set when a puzzle is finished, until it is saved
+    pub puzzle_result: Option<PuzzleResult>,
     /// When the room should be deleted. Undefined means there
     /// are still players in the room.
-    pub delete_time: Option<i32>,
+    pub delete_time: Option<Time>,
+    pub clock: Arc<dyn Clock>,
+    /// Recorded from when the game starts, until it is saved
+    pub replay: Option<Replay>,
 }
 
 impl Room {
-    pub fn new(id: String) -> Room {
+    pub fn new(id: String, clock: Arc<dyn Clock>) -> Room {
         Room {
             id,
+            access: RoomAccess::Public,
             viewers: Vec::new(),
             players: Vec::new(),
             started: false,
+            locked: false,
+            kicked: Vec::new(),
+            settings: RoomSettings::default(),
+            deck: DeckSpec::default(),
             start_time: 0,
+            seed: 0,
+            rng: StdRng::seed_from_u64(0),
             cards_left: Vec::new(),
             cards: Vec::new(),
             correct: Vec::new(),
             wrong: Vec::new(),
             game_over: false,
+            board_time: 0,
+            last_found: 0,
+            last_set_time: 0,
+            set_times: Vec::new(),
+            date: String::new(),
+            found: Vec::new(),
+            puzzle_result: None,
             delete_time: None,
+            clock,
+            replay: None,
         }
     }
 
@@ -75,24 +215,139 @@ impl Room {
             }
         }
 
-        return None;
+        None
     }
 
-    pub fn add_player(&mut self, player: Player) {
+    pub fn add_player(&mut self, mut player: Player) {
+        player.team = self.get_smallest_team();
         self.players.push(player);
         self.delete_time = None;
+        self.update_host();
+    }
+
+    /// Make sure that a connected player is the host, if there is one
+    pub fn update_host(&mut self) {
+        if self.players.iter().any(|player| player.host && player.client_id.is_some()) {
+            return;
+        }
+
+        for player in self.players.iter_mut() {
+            player.host = false;
+        }
+        if let Some(player) = self.players.iter_mut().find(|player| player.client_id.is_some() && player.bot.is_none()) {
+            player.host = true;
+        }
+    }
+
+    /// Check that the client is the host, and get their player index
+    pub fn check_host(&self, client_id: u32, action: &str) -> Result<u32, RoomError> {
+        let player_index = self.get_player_index(client_id)
+            .ok_or_else(|| RoomError::new(ErrorCode::NotPlayer, format!("Only the host can {action}")))?;
+        if !self.players[player_index as usize].host {
+            return Err(RoomError::new(ErrorCode::NotHost, format!("Only the host can {action}")));
+        }
+        Ok(player_index)
+    }
+
+    /// Get the player, checking that it isn't the host themselves
+    fn get_other_player(&self, host_index: u32, player_index: u32) -> Result<&Player, RoomError> {
+        if player_index == host_index {
+            return Err(RoomError::new(ErrorCode::NotPlayer, "That is you"));
+        }
+        self.players.get(player_index as usize)
+            .ok_or_else(|| RoomError::new(ErrorCode::NotPlayer, format!("There is no player {player_index}")))
+    }
+
+    /// Remove a player, so they can't take back their seat. Returns the
+    /// player as they were before being kicked.
+    pub fn kick_player(&mut self, client_id: u32, player_index: u32) -> Result<Player, RoomError> {
+        let host_index = self.check_host(client_id, "kick players")?;
+        let kicked = self.get_other_player(host_index, player_index)?.clone();
+
+        if self.started {
+            // Their score stays, but the seat can't be taken back
+            let player = &mut self.players[player_index as usize];
+            player.client_id = None;
+            player.token = util::new_token();
+            self.record(ReplayEvent::Leave { player: player_index });
+        } else {
+            self.players.remove(player_index as usize);
+        }
+
+        Ok(kicked)
+    }
+
+    pub fn transfer_host(&mut self, client_id: u32, player_index: u32) -> Result<(), RoomError> {
+        let host_index = self.check_host(client_id, "transfer the host")?;
+        let player = self.get_other_player(host_index, player_index)?;
+        if player.client_id.is_none() || player.bot.is_some() {
+            return Err(RoomError::new(ErrorCode::NotPlayer, format!("{} can't be the host", player.name)));
+        }
+
+        self.players[host_index as usize].host = false;
+        self.players[player_index as usize].host = true;
+        Ok(())
+    }
+
+    /// The team with the fewest players, for a new player to join
+    fn get_smallest_team(&self) -> Option<u32> {
+        (0..self.settings.teams).min_by_key(|&team| {
+            self.players.iter().filter(|player| player.team == Some(team)).count()
+        })
+    }
+
+    /// Split the players evenly between the teams, after the number of teams changes
+    pub fn assign_teams(&mut self) {
+        let teams = self.settings.teams;
+        for (i, player) in self.players.iter_mut().enumerate() {
+            player.team = if teams == 0 { None } else { Some(i as u32 % teams) };
+        }
+    }
+
+    pub fn choose_team(&mut self, client_id: u32, team: u32) -> Result<(), RoomError> {
+        let player_index = self.get_player_index(client_id)
+            .ok_or_else(|| RoomError::new(ErrorCode::NotPlayer, "Only players can choose a team"))?;
+        if self.started {
+            return Err(RoomError::new(ErrorCode::AlreadyStarted, "The game has already started"));
+        }
+        if team >= self.settings.teams {
+            return Err(RoomError::new(ErrorCode::InvalidSettings, format!("There is no team {team}")));
+        }
+
+        self.players[player_index as usize].team = Some(team);
+        Ok(())
+    }
+
+    pub fn get_team_updates(&self) -> Vec<TeamUpdate> {
+        (0..self.settings.teams)
+            .map(|team| {
+                let players = self.players.iter().filter(|player| player.team == Some(team));
+                TeamUpdate {
+                    score: players.clone().map(|player| player.score).sum(),
+                    minus_score: players.clone().map(|player| player.minus_score).sum(),
+                    points: players.map(|player| player.points).sum(),
+                }
+            })
+            .collect()
     }
 
     pub fn get_player_updates(&self) -> Vec<PlayerUpdate> {
         let mut players = Vec::new();
-        let now = util::get_now();
+        let now = self.clock.now();
         for player in self.players.iter() {
             players.push(PlayerUpdate {
                 name: player.name.clone(),
                 score: player.score,
                 minus_score: player.minus_score,
+                points: player.points,
+                series_points: player.series_points + player.points,
+                rematch: player.rematch,
+                team: player.team,
+                host: player.host,
                 connected: player.client_id.is_some(),
-                timeout: player.timeout - now,
+                timeout: relative(player.timeout, now),
+                hints: player.hints,
+                bot: player.bot,
             });
         }
 
@@ -107,7 +362,7 @@ impl Room {
             }
         }
 
-        if missing_indexes.len() == 0 {
+        if missing_indexes.is_empty() {
             return;
         }
 
@@ -122,11 +377,14 @@ impl Room {
             }
 
             self.game_over = !self.get_has_solution();
+            if self.game_over {
+                self.record(ReplayEvent::GameOver {});
+            }
             return;
         }
 
         // Replace this card if no solution
-        let replace_index = missing_indexes[rand::random_range(0..missing_indexes.len())];
+        let replace_index = missing_indexes[self.rng.random_range(0..missing_indexes.len())];
 
         for card in self.cards.iter_mut() {
             if card.is_none() {
@@ -142,11 +400,12 @@ impl Room {
 
         // There is no solution yet, so replace a card to force one
 
-        let mut index1 = rand::random_range(0..11);
+        let board_size = self.cards.len();
+        let mut index1 = self.rng.random_range(0..board_size - 1);
         if index1 >= replace_index {
             index1 += 1;
         }
-        let mut index2 = rand::random_range(0..10);
+        let mut index2 = self.rng.random_range(0..board_size - 2);
         if index2 >= min(replace_index, index1) {
             index2 += 1;
         }
@@ -154,8 +413,8 @@ impl Room {
             index2 += 1;
         }
 
-        let third_card_id = get_third_card(self.cards[index1].as_ref().unwrap(), self.cards[index2].as_ref().unwrap());
-        self.cards[replace_index] = Some(id_to_card(third_card_id));
+        let third_card_id = self.deck.get_third_card(self.cards[index1].as_ref().unwrap(), self.cards[index2].as_ref().unwrap());
+        self.cards[replace_index] = Some(self.deck.id_to_card(third_card_id));
         println!("Set card {replace_index} = {index1} + {index2}");
 
         for i in 0..self.cards_left.len() {
@@ -164,107 +423,459 @@ impl Room {
                 break;
             }
         }
-        return;
     }
 
     /// Get if the current cards have a solution
     fn get_has_solution(&self) -> bool {
-        let mut ids = HashSet::new();
-        for card in self.cards.iter() {
-            if let Some(card) = card {
-                ids.insert(card.id);
-            }
+        solver::has_set(&self.deck, &self.cards)
+    }
+
+    /// Note that the board changed, after dealing or refilling cards
+    pub fn set_board_changed(&mut self) {
+        self.board_time = max(self.clock.now(), self.start_time);
+        self.last_found = self.board_time;
+    }
+
+    /// Whether nobody else can join. Only races have more than one player,
+    /// up to `max_players`.
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= self.settings.max_players
+            || (self.settings.mode != GameMode::Race && !self.players.is_empty())
+    }
+
+    pub fn get_card_ids(&self) -> Vec<Option<i32>> {
+        self.cards.iter().map(|card| card.as_ref().map(|card| card.id)).collect()
+    }
+
+    pub fn record(&mut self, event: ReplayEvent) {
+        let now = self.clock.now();
+        if let Some(replay) = &mut self.replay {
+            replay.record(now, event);
         }
+    }
 
-        let mut result = false;
-        for (i, card_i) in self.cards.iter().enumerate() {
-            if let Some(card_i) = card_i {
-                for card_j in self.cards.iter().skip(i + 1) {
-                    if let Some(card_j) = card_j {
-                        let id = get_third_card(card_i, card_j);
-                        if ids.contains(&id) {
-                            result = true;
-                        }
-                    }
-                }
-            }
+    pub fn get_update_players_packet(&self) -> ServerMessage {
+        ServerMessage::UpdatePlayers {
+            players: self.get_player_updates(),
+            started: self.started,
+            settings: self.get_public_settings(),
+            locked: self.locked,
+        }
+    }
+
+    /// The settings without the seed while the game is running, as the seed
+    /// gives away the cards to come
+    fn get_public_settings(&self) -> RoomSettings {
+        let mut settings = self.settings.clone();
+        if self.started && !self.game_over {
+            settings.seed = None;
+        }
+        settings
+    }
+
+    /// Only public rooms are listed
+    pub fn get_summary(&self) -> Option<RoomSummary> {
+        if !matches!(self.access, RoomAccess::Public) {
+            return None;
         }
 
-        return result;
+        let connected = self.players.iter().filter(|player| player.client_id.is_some());
+        Some(RoomSummary {
+            id: self.id.clone(),
+            players: connected.clone().filter(|player| player.bot.is_none()).count() as u32,
+            bots: connected.filter(|player| player.bot.is_some()).count() as u32,
+            spectators: self.viewers.len() as u32,
+            started: self.started,
+            game_over: self.game_over,
+            locked: self.locked,
+            settings: self.get_public_settings(),
+        })
     }
 
     pub fn get_update_game_packet(&self, player_index: Option<u32>) -> ServerMessage {
-        let packet = ServerMessage::UpdateGame {
+        let now = self.clock.now();
+        let player_state = player_index.map(|player_index| {
+            let picks = self.correct.iter()
+                .chain(self.wrong.iter())
+                .filter(|pick| pick.player == player_index)
+                .map(|pick| pick.to_update(now))
+                .collect();
+            PlayerState {
+                player_index,
+                timeout: max(relative(self.players[player_index as usize].timeout, now), 0),
+                picks,
+                can_pick: self.check_can_pick(player_index).is_ok(),
+                hint: self.players[player_index as usize].hint.clone(),
+            }
+        });
+
+        ServerMessage::UpdateGame {
             players: self.get_player_updates(),
-            cards: self.cards.iter().map(|card| card.as_ref().map(|card| card.id)).collect(),
-            wrong: self.wrong.to_vec(),
-            correct: self.correct.to_vec(),
+            cards: self.get_card_ids(),
+            wrong: self.wrong.iter().map(|pick| pick.to_update(now)).collect(),
+            correct: self.correct.iter().map(|pick| pick.to_update(now)).collect(),
             game_over: self.game_over,
-            start_time: self.start_time - util::get_now(),
-        };
-
-        if let Some(_player_index) = player_index {
-            // Add playerState
+            start_time: relative(self.start_time, now),
+            seed: self.game_over.then_some(self.seed),
+            player_state,
+            found: self.found.clone(),
+            rankings: self.get_rankings(),
+            teams: self.get_team_updates(),
         }
+    }
 
-        packet
+    /// Player indexes, from the most points to the least. Ties go to the
+    /// player with fewer wrong picks.
+    pub fn get_rankings(&self) -> Vec<u32> {
+        let mut rankings: Vec<u32> = (0..self.players.len() as u32).collect();
+        rankings.sort_by_key(|&i| {
+            let player = &self.players[i as usize];
+            (-player.points, player.minus_score)
+        });
+        rankings
     }
 
     pub fn start(&mut self) {
+        let is_puzzle = self.settings.mode == GameMode::Puzzle;
+        let day = util::today();
+        if is_puzzle {
+            // Everybody gets the same board on the same day
+            self.settings.deck = DeckVariant::Classic;
+            self.settings.board_size = PUZZLE_BOARD_SIZE;
+            self.settings.seed = Some(day as u32);
+            self.date = util::format_day(day);
+        }
+
         self.started = true;
-        self.start_time = util::get_now() + 3000;
-        // Attributes: color (3), amount (3), shape (3), fill (3)
-        let mut card_ids: Vec<_> = (0..81).collect();
-        card_ids.shuffle(&mut rand::rng());
-        self.cards_left = card_ids
-            .iter()
-            .map(|&id| id_to_card(id))
-            .collect();
-        self.cards = iter::repeat(None).take(12).collect();
-        self.add_cards();
-    }
-
-    pub fn pick_cards(&mut self, client_id: u32, card_indexes: &[usize]) {
-        let player_index = self.get_player_index(client_id).unwrap();
-
-        let is_match = check_match(&self.cards[card_indexes[0]], &self.cards[card_indexes[1]], &self.cards[card_indexes[2]]);
-        if is_match {
-            // Check that somebody else didn't already use any of the cards
-            for pick in self.correct.iter() {
-                for i in 0..3 {
-                    if pick.cards.contains(&card_indexes[i]) {
-                        return;
+        self.start_time = self.clock.now() + Time::from(self.settings.countdown);
+        self.deck = self.settings.deck.spec();
+        self.seed = self.settings.seed.unwrap_or_else(rand::random);
+        self.rng = StdRng::seed_from_u64(self.seed.into());
+        println!("Room {} started with seed {}", self.id, self.seed);
+        self.replay = Some(Replay::new(util::new_token(), self.clock.now()));
+        self.record(ReplayEvent::Start {
+            room_id: self.id.clone(),
+            settings: self.settings.clone(),
+            seed: self.seed,
+            players: self.players.iter().map(|player| player.name.clone()).collect(),
+        });
+        if is_puzzle {
+            let board = self.deck.generate_board(PUZZLE_BOARD_SIZE, PUZZLE_SETS, &mut self.rng)
+                .expect("There is always a classic board with this many sets");
+            self.cards = board.into_iter().map(Some).collect();
+        } else {
+            let mut card_ids: Vec<_> = (0..self.deck.card_count()).collect();
+            card_ids.shuffle(&mut self.rng);
+            self.cards_left = card_ids
+                .iter()
+                .map(|&id| self.deck.id_to_card(id))
+                .collect();
+            self.cards = vec![None; self.settings.board_size];
+            self.add_cards();
+        }
+        self.set_board_changed();
+        self.record(ReplayEvent::Deal { cards: self.get_card_ids() });
+    }
+
+    /// Remove highlights that have expired, and refill the cards of correct
+    /// picks. Returns whether anything changed, and whether cards were refilled.
+    pub fn expire_picks(&mut self) -> (bool, bool) {
+        let now = self.clock.now();
+
+        let mut updated = false;
+        let mut refill = false;
+        let mut i = 0;
+        while i < self.wrong.len() {
+            if now > self.wrong[i].expire {
+                self.wrong.remove(i);
+                updated = true;
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut i = 0;
+        while i < self.correct.len() {
+            if now > self.correct[i].expire {
+                // Puzzles keep the same cards until the end
+                if self.settings.mode != GameMode::Puzzle {
+                    for &card_index in &self.correct[i].cards {
+                        self.cards[card_index] = None;
                     }
+                    refill = true;
                 }
+                self.correct.remove(i);
+                updated = true;
+            } else {
+                i += 1;
             }
         }
 
+        if refill {
+            // Refill the spaces left by correct picks
+            self.add_cards();
+            self.set_board_changed();
+            self.clear_hints();
+            self.record(ReplayEvent::Refill { cards: self.get_card_ids() });
+        }
+        (updated, refill)
+    }
+
+    /// Check that the player is allowed to pick any cards right now
+    fn check_can_pick(&self, player_index: u32) -> Result<(), RoomError> {
+        let now = self.clock.now();
+        if !self.started {
+            return Err(RoomError::new(ErrorCode::NotStarted, "The game has not started"));
+        }
+        if now < self.start_time {
+            let wait = self.start_time - now;
+            return Err(RoomError::new(ErrorCode::NotStarted, format!("The game starts in {wait} ms")));
+        }
+        if self.game_over {
+            return Err(RoomError::new(ErrorCode::GameOver, "The game is over"));
+        }
+
+        let timeout = self.players[player_index as usize].timeout;
+        if now < timeout {
+            let wait = timeout - now;
+            return Err(RoomError::new(ErrorCode::TimedOut, format!("Timed out for another {wait} ms")));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the client is allowed to pick these cards
+    fn validate_pick(&self, client_id: u32, card_indexes: &[usize]) -> Result<u32, RoomError> {
+        let player_index = self.get_player_index(client_id)
+            .ok_or_else(|| RoomError::new(ErrorCode::NotPlayer, "Only players can pick cards"))?;
+        self.check_can_pick(player_index)?;
+
+        if card_indexes.len() != 3 {
+            return Err(RoomError::new(ErrorCode::InvalidPick, "Exactly 3 cards must be picked"));
+        }
+
+        for (i, &card_index) in card_indexes.iter().enumerate() {
+            if card_index >= self.cards.len() {
+                return Err(RoomError::new(ErrorCode::InvalidPick, format!("Card {card_index} is not on the board")));
+            }
+            if card_indexes[..i].contains(&card_index) {
+                return Err(RoomError::new(ErrorCode::InvalidPick, format!("Card {card_index} was picked twice")));
+            }
+            if self.cards[card_index].is_none() {
+                return Err(RoomError::new(ErrorCode::EmptySlot, format!("There is no card at {card_index}")));
+            }
+        }
+
+        Ok(player_index)
+    }
+
+    pub fn pick_cards(&mut self, client_id: u32, card_indexes: &[usize]) -> Result<(), RoomError> {
+        let player_index = self.validate_pick(client_id, card_indexes)?;
+        let now = self.clock.now();
+
+        let is_match = self.deck.check_match(&self.cards[card_indexes[0]], &self.cards[card_indexes[1]], &self.cards[card_indexes[2]]);
+        let is_puzzle = self.settings.mode == GameMode::Puzzle;
+        let mut is_won = false;
+        let mut set = card_indexes.to_vec();
+        set.sort();
         if is_match {
-            self.correct.push(PickCards {
+            // Check that somebody else didn't already use any of the cards.
+            // Sets in a puzzle share cards, so only the same set is taken.
+            let taken = if is_puzzle {
+                self.found.contains(&set)
+            } else {
+                self.correct.iter()
+                    .any(|pick| card_indexes.iter().any(|card_index| pick.cards.contains(card_index)))
+            };
+            if taken {
+                self.record(ReplayEvent::Pick {
+                    player: player_index,
+                    cards: card_indexes.to_vec(),
+                    outcome: PickOutcome::Taken,
+                });
+                let message = if is_puzzle { "This set was already found" } else { "Somebody else already picked these cards" };
+                return Err(RoomError::new(ErrorCode::CardsTaken, message));
+            }
+        }
+
+        if is_match {
+            self.correct.push(Pick {
                 player: player_index,
                 cards: card_indexes.to_vec(),
-                expire:  get_now() + COLOR_EXPIRE_MS,
+                expire: now + Time::from(self.settings.highlight_expire),
             });
-            self.players[player_index as usize].score += 1;
+            let scoring = &self.settings.scoring;
+            let player = &mut self.players[player_index as usize];
+            player.score += 1;
+            player.streak += 1;
+            player.points += scoring.set_points(player.streak, now - self.board_time);
+            // In teams, the team's points count
+            let points = match player.team {
+                Some(team) => self.get_team_updates()[team as usize].points,
+                None => player.points,
+            };
+            is_won = self.settings.scoring.is_won(points);
+            self.set_times.push(now - self.last_found);
+            self.last_found = now;
+            self.last_set_time = now;
+            if is_puzzle {
+                self.found.push(set);
+            }
+            // Hints about these cards can't be used any more
+            for player in self.players.iter_mut() {
+                if player.hint.iter().any(|card_index| card_indexes.contains(card_index)) {
+                    player.hint.clear();
+                }
+            }
         } else {
-            self.wrong.push(PickCards {
+            self.wrong.push(Pick {
                 player: player_index,
                 cards: card_indexes.to_vec(),
-                expire:  get_now() + COLOR_EXPIRE_MS,
+                expire: now + Time::from(self.settings.highlight_expire),
+            });
+            let player = &mut self.players[player_index as usize];
+            player.minus_score += 1;
+            player.streak = 0;
+            player.points += self.settings.scoring.wrong_points();
+            let timeout = now + Time::from(self.settings.penalty_timeout);
+            player.timeout = timeout;
+            if self.settings.team_penalty {
+                if let Some(team) = player.team {
+                    for player in self.players.iter_mut().filter(|player| player.team == Some(team)) {
+                        player.timeout = timeout;
+                    }
+                }
+            }
+        }
+
+        self.record(ReplayEvent::Pick {
+            player: player_index,
+            cards: card_indexes.to_vec(),
+            outcome: if is_match { PickOutcome::Correct } else { PickOutcome::Wrong },
+        });
+
+        let is_puzzle_done = is_puzzle && self.found.len() == PUZZLE_SETS;
+        if is_won || is_puzzle_done {
+            self.game_over = true;
+            self.record(ReplayEvent::GameOver {});
+        }
+        if is_puzzle_done {
+            let player = &self.players[player_index as usize];
+            self.puzzle_result = Some(PuzzleResult {
+                name: player.name.clone(),
+                time: relative(now, self.start_time),
+                mistakes: player.minus_score,
+                hints: player.hints,
             });
-            self.players[player_index as usize].minus_score += 1;
-            self.players[player_index as usize].timeout = get_now() + TIMEOUT_MS;
         }
+        Ok(())
+    }
+
+    /// Reveal one more card of a set to the player, at the cost of some points
+    pub fn request_hint(&mut self, client_id: u32) -> Result<(), RoomError> {
+        let player_index = self.get_player_index(client_id)
+            .ok_or_else(|| RoomError::new(ErrorCode::NotPlayer, "Only players can ask for hints"))?;
+        let cost = self.settings.hint_cost
+            .ok_or_else(|| RoomError::new(ErrorCode::HintsDisabled, "Hints are disabled in this room"))?;
+        self.check_can_pick(player_index)?;
+
+        let hint = &self.players[player_index as usize].hint;
+        if hint.len() >= 2 {
+            return Err(RoomError::new(ErrorCode::NoHint, "The hint already shows 2 cards"));
+        }
+
+        // Cards that were picked correctly are about to be replaced
+        let taken: Vec<usize> = self.correct.iter().flat_map(|pick| pick.cards.iter().copied()).collect();
+        let is_available = |set: &solver::Set| match self.settings.mode {
+            GameMode::Puzzle => !self.found.contains(&set.to_vec()),
+            _ => !set.iter().any(|card_index| taken.contains(card_index)),
+        };
+        // Take the first set rather than a random one, so the deal stays the same for the seed
+        let set = solver::find_sets(&self.deck, &self.cards)
+            .into_iter()
+            .find(|set| is_available(set) && hint.iter().all(|card_index| set.contains(card_index)))
+            .ok_or_else(|| RoomError::new(ErrorCode::NoHint, "There is no set to give a hint for"))?;
+        let card_index = *set.iter().find(|card_index| !hint.contains(card_index)).unwrap();
+
+        let player = &mut self.players[player_index as usize];
+        player.hint.push(card_index);
+        player.hints += 1;
+        player.points -= cost;
+        let cards = player.hint.clone();
+        self.record(ReplayEvent::Hint { player: player_index, cards });
+        Ok(())
+    }
+
+    /// Forget the hints, once the cards they are about have changed
+    pub fn clear_hints(&mut self) {
+        for player in self.players.iter_mut() {
+            player.hint.clear();
+        }
+    }
+
+    /// How the player did in a practice game
+    pub fn get_practice_summary_packet(&self, player_index: u32) -> ServerMessage {
+        let player = &self.players[player_index as usize];
+        ServerMessage::PracticeSummary {
+            set_times: self.set_times.iter().map(|&time| time as i32).collect(),
+            // The game is only over once the last highlight expires, which doesn't count
+            total_time: relative(self.last_set_time, self.start_time),
+            mistakes: player.minus_score,
+            hints: player.hints,
+        }
+    }
+
+    /// Vote for a rematch. Returns whether enough players have voted to start it.
+    pub fn vote_rematch(&mut self, client_id: u32) -> Result<bool, RoomError> {
+        let player_index = self.get_player_index(client_id)
+            .ok_or_else(|| RoomError::new(ErrorCode::NotPlayer, "Only players can vote for a rematch"))?;
+        if !self.game_over {
+            return Err(RoomError::new(ErrorCode::NotOver, "The game isn't over yet"));
+        }
+        if self.settings.mode == GameMode::Puzzle {
+            return Err(RoomError::new(ErrorCode::RematchDisabled, "The daily puzzle can only be played once"));
+        }
+
+        let player = &mut self.players[player_index as usize];
+        player.rematch = true;
+        if player.host {
+            return Ok(true);
+        }
+        Ok(self.players.iter()
+            .filter(|player| player.client_id.is_some() && player.bot.is_none())
+            .all(|player| player.rematch))
+    }
+
+    /// Start a new game with the same players, keeping their points for the series
+    pub fn rematch(&mut self) {
+        for player in self.players.iter_mut() {
+            player.series_points += player.points;
+            player.score = 0;
+            player.minus_score = 0;
+            player.points = 0;
+            player.streak = 0;
+            player.timeout = 0;
+            player.hints = 0;
+            player.hint.clear();
+            player.rematch = false;
+        }
+
+        self.correct.clear();
+        self.wrong.clear();
+        self.game_over = false;
+        self.set_times.clear();
+        self.found.clear();
+        self.puzzle_result = None;
+        self.start();
     }
 
     pub fn remove_client(&mut self, client_id: u32) {
         self.viewers.retain(|id| id != &client_id);
 
         if self.started {
-            for player in self.players.iter_mut() {
-                if player.client_id == Some(client_id) {
-                    player.client_id = None;
-                }
+            if let Some(player_index) = self.get_player_index(client_id) {
+                self.players[player_index as usize].client_id = None;
+                self.record(ReplayEvent::Leave { player: player_index });
             }
         } else {
             let player_index = self.get_player_index(client_id);
@@ -272,23 +883,202 @@ impl Room {
                 self.players.remove(player_index as usize);
             }
         }
+
+        self.update_host();
     }
 
     pub fn check_empty(&mut self) {
         // Queue the room to be deleted
         let mut is_empty = true;
         for player in self.players.iter() {
-            if player.client_id.is_some() {
+            // Bots don't keep the room open
+            if player.client_id.is_some() && player.bot.is_none() {
                 is_empty = false;
             }
         }
 
         if is_empty {
             if self.game_over {
-                self.delete_time = Some(get_now() + 1000);
+                self.delete_time = Some(self.clock.now() + 1000);
             } else {
-                self.delete_time = Some(get_now() + 30 * 60 * 1000);
+                self.delete_time = Some(self.clock.now() + 30 * 60 * 1000);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    /// A started race with 2 players, and a clock that only moves when told to
+    fn start_room() -> (Room, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let mut room = Room::new("test".to_string(), clock.clone());
+        room.settings.seed = Some(1);
+        room.add_player(Player::new(1, "a".to_string()));
+        room.add_player(Player::new(2, "b".to_string()));
+        room.start();
+        (room, clock)
+    }
+
+    fn find_set(room: &Room) -> Vec<usize> {
+        solver::find_sets(&room.deck, &room.cards)[0].to_vec()
+    }
+
+    fn find_wrong_cards(room: &Room) -> Vec<usize> {
+        for i in 2..room.cards.len() {
+            let cards = vec![0, 1, i];
+            if !room.deck.check_match(&room.cards[0], &room.cards[1], &room.cards[i]) {
+                return cards;
+            }
+        }
+        panic!("Every card makes a set with the first 2");
+    }
+
+    fn pick_error(room: &mut Room, client_id: u32, cards: &[usize]) -> Option<ErrorCode> {
+        room.pick_cards(client_id, cards).err().map(|error| error.code)
+    }
+
+    /// Cards, correct and wrong picks, and each player's score, minus score and timeout
+    type Snapshot = (Vec<Option<i32>>, usize, usize, Vec<(i32, i32, Time)>);
+
+    /// Everything that a pick could change
+    fn snapshot(room: &Room) -> Snapshot {
+        let players = room.players.iter().map(|player| (player.score, player.minus_score, player.timeout)).collect();
+        (room.get_card_ids(), room.correct.len(), room.wrong.len(), players)
+    }
+
+    #[test]
+    fn invalid_picks_are_refused_without_changing_anything() {
+        let (mut room, clock) = start_room();
+        clock.advance(Time::from(room.settings.countdown));
+        room.cards[5] = None;
+        let before = snapshot(&room);
+
+        assert_eq!(pick_error(&mut room, 1, &[0, 1]), Some(ErrorCode::InvalidPick));
+        assert_eq!(pick_error(&mut room, 1, &[0, 1, 2, 3]), Some(ErrorCode::InvalidPick));
+        assert_eq!(pick_error(&mut room, 1, &[0, 1, 1]), Some(ErrorCode::InvalidPick));
+        assert_eq!(pick_error(&mut room, 1, &[0, 1, 99]), Some(ErrorCode::InvalidPick));
+        assert_eq!(pick_error(&mut room, 1, &[0, 1, 5]), Some(ErrorCode::EmptySlot));
+        assert_eq!(pick_error(&mut room, 3, &[0, 1, 2]), Some(ErrorCode::NotPlayer));
+        assert_eq!(snapshot(&room), before);
+    }
+
+    #[test]
+    fn picks_are_refused_before_the_game_starts() {
+        let clock = Arc::new(ManualClock::new());
+        let mut room = Room::new("test".to_string(), clock);
+        room.add_player(Player::new(1, "a".to_string()));
+        let before = snapshot(&room);
+
+        assert_eq!(pick_error(&mut room, 1, &[0, 1, 2]), Some(ErrorCode::NotStarted));
+        assert_eq!(snapshot(&room), before);
+    }
+
+    /// Pick the first set until the game is over, and get every board on the way
+    fn play_through(seed: u32) -> Vec<Vec<Option<i32>>> {
+        let (mut room, clock) = start_room();
+        room.settings.seed = Some(seed);
+        room.start();
+        clock.advance(Time::from(room.settings.countdown));
+
+        let mut boards = vec![room.get_card_ids()];
+        while !room.game_over {
+            room.pick_cards(1, &find_set(&room)).unwrap();
+            clock.advance(Time::from(room.settings.highlight_expire) + 1);
+            room.expire_picks();
+            boards.push(room.get_card_ids());
+        }
+        boards
+    }
+
+    #[test]
+    fn the_same_seed_deals_the_same_cards() {
+        let boards = play_through(7);
+        // The whole deck was dealt, including any cards swapped in to force a set
+        assert!(boards.len() > 20);
+        assert_eq!(boards, play_through(7));
+        assert_ne!(boards[0], play_through(8)[0]);
+    }
+
+    #[test]
+    fn practice_time_stops_at_the_last_set() {
+        let clock = Arc::new(ManualClock::new());
+        let mut room = Room::new("test".to_string(), clock.clone());
+        room.settings.mode = GameMode::Practice;
+        room.add_player(Player::new(1, "a".to_string()));
+        room.start();
+        clock.advance(Time::from(room.settings.countdown));
+
+        let mut sets = 0;
+        while !room.game_over {
+            clock.advance(1000);
+            room.pick_cards(1, &find_set(&room)).unwrap();
+            sets += 1;
+            clock.advance(Time::from(room.settings.highlight_expire) + 1);
+            room.expire_picks();
+        }
+
+        let ServerMessage::PracticeSummary { set_times, total_time, .. } = room.get_practice_summary_packet(0) else {
+            panic!("Not a practice summary");
+        };
+        assert_eq!(set_times.len(), sets);
+        assert_eq!(set_times[0], 1000);
+        let highlight = room.settings.highlight_expire + 1;
+        assert_eq!(total_time, sets as i32 * (1000 + highlight) - highlight);
+    }
+
+    #[test]
+    fn picks_are_refused_during_the_countdown() {
+        let (mut room, clock) = start_room();
+        let set = find_set(&room);
+
+        clock.advance(Time::from(room.settings.countdown) - 1);
+        assert_eq!(pick_error(&mut room, 1, &set), Some(ErrorCode::NotStarted));
+
+        clock.advance(1);
+        assert_eq!(pick_error(&mut room, 1, &set), None);
+        assert_eq!(room.players[0].score, 1);
+    }
+
+    #[test]
+    fn wrong_picks_time_out_the_player() {
+        let (mut room, clock) = start_room();
+        clock.advance(Time::from(room.settings.countdown));
+        let wrong = find_wrong_cards(&room);
+        assert_eq!(pick_error(&mut room, 1, &wrong), None);
+        assert_eq!(room.players[0].minus_score, 1);
+
+        clock.advance(Time::from(room.settings.penalty_timeout) - 1);
+        assert_eq!(pick_error(&mut room, 1, &wrong), Some(ErrorCode::TimedOut));
+        // Only the player who picked is timed out
+        assert_eq!(pick_error(&mut room, 2, &wrong), None);
+
+        clock.advance(1);
+        assert_eq!(pick_error(&mut room, 1, &wrong), None);
+        assert_eq!(room.players[0].minus_score, 2);
+    }
+
+    #[test]
+    fn correct_picks_are_replaced_once_the_highlight_expires() {
+        let (mut room, clock) = start_room();
+        clock.advance(Time::from(room.settings.countdown));
+        let set = find_set(&room);
+        let old_ids: Vec<_> = set.iter().map(|&i| room.cards[i].as_ref().unwrap().id).collect();
+        room.pick_cards(1, &set).unwrap();
+
+        clock.advance(Time::from(room.settings.highlight_expire));
+        assert_eq!(room.expire_picks(), (false, false));
+        assert_eq!(room.correct.len(), 1);
+
+        clock.advance(1);
+        assert_eq!(room.expire_picks(), (true, true));
+        assert!(room.correct.is_empty());
+        for (&card_index, old_id) in set.iter().zip(old_ids) {
+            assert_ne!(room.cards[card_index].as_ref().map(|card| card.id), Some(old_id));
+        }
+        assert!(solver::has_set(&room.deck, &room.cards));
+    }
+}
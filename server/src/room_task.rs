@@ -0,0 +1,701 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{interval, Duration};
+
+use crate::bot::BotDifficulty;
+use crate::client::Client;
+use crate::clock::Clock;
+use crate::messages::{ErrorCode, RejectReason, RoomSummary, ServerMessage};
+use crate::puzzle::PuzzleResult;
+use crate::replay::ReplayEvent;
+use crate::room::{Player, Room, RoomAccess, RoomError};
+use crate::server_state::ServerState;
+use crate::settings::{GameMode, RoomSettings};
+
+const COMMAND_QUEUE_SIZE: usize = 64;
+const TICK_MS: u64 = 100;
+
+/// A request from a client connection to the room it is in
+#[derive(Debug)]
+pub enum RoomCommand {
+    /// The password or invite code is needed for private rooms
+    View { client: Client, password: Option<String>, invite_code: Option<String> },
+    Join { client_id: u32, name: String, token: Option<String> },
+    /// Stop playing, but keep viewing the room
+    Leave { client_id: u32 },
+    Disconnect { client_id: u32 },
+    UpdateSettings { client_id: u32, settings: RoomSettings },
+    StartGame { client_id: u32 },
+    PickCards { client_id: u32, cards: Vec<usize> },
+    RequestHint { client_id: u32 },
+    ChooseTeam { client_id: u32, team: u32 },
+    KickPlayer { client_id: u32, player_index: u32 },
+    TransferHost { client_id: u32, player_index: u32 },
+    LockLobby { client_id: u32, locked: bool },
+    Rematch { client_id: u32 },
+    /// `bot` is the client of a bot task that has already been started
+    AddBot { client_id: u32, bot: Client, difficulty: BotDifficulty },
+}
+
+/// Used to send commands to a running room task
+#[derive(Clone, Debug)]
+pub struct RoomHandle {
+    sender: mpsc::Sender<RoomCommand>,
+}
+
+impl RoomHandle {
+    /// Start the task for a new room
+    pub fn spawn(room_id: String, access: RoomAccess, clock: Arc<dyn Clock>, state: Arc<Mutex<ServerState>>) -> RoomHandle {
+        let (sender, receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let mut room = Room::new(room_id, clock);
+        room.access = access;
+        // Delete the room if nobody joins
+        room.check_empty();
+        let task = RoomTask {
+            room,
+            clients: HashMap::new(),
+            state,
+            summary: None,
+        };
+        tokio::spawn(task.run(receiver));
+        RoomHandle { sender }
+    }
+
+    pub async fn send(&self, command: RoomCommand) {
+        _ = self.sender.send(command).await;
+    }
+
+    /// Wait for space in the queue, so that a command can be sent without
+    /// waiting. Returns None if the room task has stopped.
+    pub async fn reserve(&self) -> Option<mpsc::Permit<'_, RoomCommand>> {
+        self.sender.reserve().await.ok()
+    }
+
+    pub fn is_same_room(&self, other: &RoomHandle) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+}
+
+/// Owns a room, and the clients that are viewing or playing in it
+struct RoomTask {
+    room: Room,
+    clients: HashMap<u32, Client>,
+    /// Used to remove the room once it is deleted
+    state: Arc<Mutex<ServerState>>,
+    /// What the room list last showed about this room
+    summary: Option<RoomSummary>,
+}
+
+impl RoomTask {
+    async fn run(mut self, mut receiver: mpsc::Receiver<RoomCommand>) {
+        let mut tick_interval = interval(Duration::from_millis(TICK_MS));
+        loop {
+            tokio::select! {
+                command = receiver.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                _ = tick_interval.tick() => {
+                    if self.check_delete_room(&receiver).await {
+                        break;
+                    }
+                    self.tick().await;
+                }
+            }
+            self.update_summary().await;
+        }
+    }
+
+    /// Update the room list if anything in it changed
+    async fn update_summary(&mut self) {
+        let summary = self.room.get_summary();
+        if summary == self.summary {
+            return;
+        }
+
+        let (packet, subscribers) = self.state.lock().await.set_room_summary(&self.room.id, summary.clone());
+        for client in subscribers {
+            client.send_packet(packet.clone());
+        }
+        self.summary = summary;
+    }
+
+    async fn handle_command(&mut self, command: RoomCommand) {
+        match command {
+            RoomCommand::View { client, password, invite_code } => self.view_room(client, password, invite_code),
+            RoomCommand::Join { client_id, name, token } => self.join_room(client_id, name, token),
+            RoomCommand::Leave { client_id } => self.leave_view_room(client_id),
+            RoomCommand::Disconnect { client_id } => self.disconnect(client_id),
+            RoomCommand::UpdateSettings { client_id, settings } => self.update_settings(client_id, settings),
+            RoomCommand::StartGame { client_id } => self.start_game(client_id),
+            RoomCommand::PickCards { client_id, cards } => self.pick_cards(client_id, &cards),
+            RoomCommand::RequestHint { client_id } => self.request_hint(client_id),
+            RoomCommand::ChooseTeam { client_id, team } => self.choose_team(client_id, team),
+            RoomCommand::KickPlayer { client_id, player_index } => self.kick_player(client_id, player_index),
+            RoomCommand::TransferHost { client_id, player_index } => self.transfer_host(client_id, player_index),
+            RoomCommand::LockLobby { client_id, locked } => self.lock_lobby(client_id, locked),
+            RoomCommand::Rematch { client_id } => self.rematch(client_id).await,
+            RoomCommand::AddBot { client_id, bot, difficulty } => self.add_bot(client_id, bot, difficulty),
+        }
+    }
+
+    async fn tick(&mut self) {
+        let (updated, refill) = self.room.expire_picks();
+        if updated {
+            self.send_update_game_all();
+
+            if refill && self.room.game_over && self.room.settings.mode == GameMode::Practice {
+                self.send_practice_summary();
+            }
+        }
+
+        if let Some(result) = self.room.puzzle_result.take() {
+            self.save_puzzle_result(result).await;
+        }
+        if self.room.game_over {
+            self.save_replay().await;
+        }
+    }
+
+    /// Keep the replay once the game is over, or the room is reset
+    async fn save_replay(&mut self) {
+        if let Some(replay) = self.room.replay.take() {
+            let id = replay.id.clone();
+            self.state.lock().await.replays.insert(replay);
+            println!("Saved replay {id}");
+
+            let packet = ServerMessage::ReplaySaved { id };
+            for player in self.room.players.iter() {
+                if let Some(client_id) = player.client_id {
+                    self.send_packet(client_id, packet.clone());
+                }
+            }
+            for &client_id in self.room.viewers.iter() {
+                self.send_packet(client_id, packet.clone());
+            }
+        }
+    }
+
+    async fn save_puzzle_result(&mut self, result: PuzzleResult) {
+        let date = self.room.date.clone();
+        let results = {
+            let mut state = self.state.lock().await;
+            let name = result.name.clone();
+            if !state.puzzle_results.insert(&date, result) {
+                println!("Ignored another puzzle result from {name} on {date}");
+            }
+            state.puzzle_results.get(&date)
+        };
+
+        let packet = ServerMessage::PuzzleResults { date, results };
+        for player in self.room.players.iter() {
+            if let Some(client_id) = player.client_id {
+                self.send_packet(client_id, packet.clone());
+            }
+        }
+        for &client_id in self.room.viewers.iter() {
+            self.send_packet(client_id, packet.clone());
+        }
+    }
+
+    /// Once the delete time has passed, remove the room, or replace it with a
+    /// new one if anybody is still viewing. Returns whether the room was removed.
+    async fn check_delete_room(&mut self, receiver: &mpsc::Receiver<RoomCommand>) -> bool {
+        let now = self.room.clock.now();
+
+        if let Some(delete_time) = self.room.delete_time {
+            if now < delete_time {
+                return false;
+            }
+
+            let room_id = self.room.id.clone();
+            if self.room.viewers.is_empty() {
+                // New viewers are sent while the server state is locked, so
+                // none can arrive after checking the queue
+                let mut state = self.state.lock().await;
+                if !receiver.is_empty() {
+                    return false;
+                }
+                if let Some(replay) = self.room.replay.take() {
+                    state.replays.insert(replay);
+                }
+                state.remove_room(&room_id);
+                let (packet, subscribers) = state.set_room_summary(&room_id, None);
+                drop(state);
+                for client in subscribers {
+                    client.send_packet(packet.clone());
+                }
+                println!("Deleted room {room_id}");
+                return true;
+            } else {
+                self.save_replay().await;
+                let mut new_room = Room::new(room_id.clone(), self.room.clock.clone());
+                new_room.access = self.room.access.clone();
+                for &viewer in self.room.viewers.iter() {
+                    new_room.viewers.push(viewer);
+                }
+                self.room = new_room;
+                // Dropping the clients of bots stops them
+                let viewers = &self.room.viewers;
+                self.clients.retain(|client_id, _| viewers.contains(client_id));
+                println!("Reset room {room_id}");
+                self.send_update_players(true, true);
+            }
+        }
+
+        false
+    }
+
+    fn send_packet(&self, client_id: u32, data: ServerMessage) {
+        if let Some(client) = self.clients.get(&client_id) {
+            client.send_packet(data);
+        }
+    }
+
+    fn view_room(&mut self, client: Client, password: Option<String>, invite_code: Option<String>) {
+        let client_id = client.id;
+        // Kept until it disconnects, so that it can be told why it can't join
+        self.clients.insert(client_id, client);
+        if !self.room.access.allows(password.as_deref(), invite_code.as_deref()) {
+            println!("[{client_id}] Wrong password or invite code for room {}", self.room.id);
+            return self.send_packet(client_id, RejectReason::WrongPassword.into());
+        }
+        if !self.add_viewer(client_id) {
+            return;
+        }
+
+        let packet = self.room.get_update_players_packet();
+        self.send_packet(client_id, packet);
+    }
+
+    /// Let the client watch the room, if there are few enough spectators.
+    /// Otherwise it is told why it can't. Returns whether it was added.
+    fn add_viewer(&mut self, client_id: u32) -> bool {
+        if self.room.viewers.contains(&client_id) {
+            return true;
+        }
+        if self.room.viewers.len() >= self.room.settings.max_spectators {
+            println!("[{client_id}] Too many spectators in room {}", self.room.id);
+            self.send_packet(client_id, RejectReason::TooManySpectators.into());
+            return false;
+        }
+        self.room.viewers.push(client_id);
+        true
+    }
+
+    fn join_room(&mut self, client_id: u32, client_name: String, token: Option<String>) {
+        let ip = self.clients.get(&client_id).and_then(|client| client.ip);
+        let room = &mut self.room;
+        let mut result = Ok(());
+        let mut old_client_id = None;
+        if room.get_player_index(client_id).is_some() {
+            // Each connection only gets one seat
+            result = Err(RejectReason::AlreadyJoined);
+        } else if !room.viewers.contains(&client_id) {
+            // Only clients that were let in to view the room can join it
+            result = Err(RejectReason::NotViewing);
+        } else if ip.is_some_and(|ip| room.kicked.contains(&ip)) {
+            result = Err(RejectReason::Kicked);
+        } else if room.started {
+            result = Err(RejectReason::Started);
+            // Only the client that was given the token can take back the seat
+            for player in room.players.iter_mut() {
+                if token.as_ref() == Some(&player.token) {
+                    // The old connection hasn't dropped yet, so it can only watch
+                    old_client_id = player.client_id.replace(client_id).filter(|&id| id != client_id);
+                    result = Ok(());
+                    room.delete_time = None;
+                }
+            }
+            if result.is_ok() {
+                let player_index = room.get_player_index(client_id).unwrap();
+                room.record(ReplayEvent::Join { player: player_index });
+                room.update_host();
+            }
+        } else if room.is_full() {
+            result = Err(RejectReason::RoomFull);
+        } else if room.locked {
+            result = Err(RejectReason::Locked);
+        } else if room.players.iter().any(|player| player.name == client_name) {
+            result = Err(RejectReason::NameTaken);
+        } else {
+            room.add_player(Player::new(client_id, client_name));
+        }
+
+        if let Err(reason) = result {
+            self.send_packet(client_id, reason.into());
+        } else {
+            room.viewers.retain(|&id| id != client_id);
+            let player_index = room.get_player_index(client_id).unwrap();
+            let packet = ServerMessage::Joined {
+                token: room.players[player_index as usize].token.clone(),
+                player_index,
+            };
+            self.send_packet(client_id, packet);
+
+            if self.room.started {
+                let packet = self.room.get_update_game_packet(Some(player_index));
+                self.send_packet(client_id, packet);
+            }
+            if let Some(old_client_id) = old_client_id {
+                self.add_viewer(old_client_id);
+            }
+
+            self.send_update_players(true, true);
+        }
+    }
+
+    fn leave_view_room(&mut self, client_id: u32) {
+        self.room.remove_client(client_id);
+        if self.add_viewer(client_id) {
+            let packet = self.room.get_update_players_packet();
+            self.send_packet(client_id, packet);
+        }
+
+        self.room.check_empty();
+        self.send_update_players(true, true);
+    }
+
+    fn disconnect(&mut self, client_id: u32) {
+        self.room.remove_client(client_id);
+        self.clients.remove(&client_id);
+
+        self.room.check_empty();
+        self.send_update_players(true, true);
+    }
+
+    fn send_update_players(&self, send_to_players: bool, send_to_viewers: bool) {
+        let packet = self.room.get_update_players_packet();
+        if send_to_players {
+            for player in self.room.players.iter() {
+                if let Some(client_id) = player.client_id {
+                    self.send_packet(client_id, packet.clone());
+                }
+            }
+        }
+        if send_to_viewers {
+            for &client_id in self.room.viewers.iter() {
+                self.send_packet(client_id, packet.clone());
+            }
+        }
+    }
+
+    fn update_settings(&mut self, client_id: u32, settings: RoomSettings) {
+        let room = &mut self.room;
+        let error = if let Err(error) = room.check_host(client_id, "change the settings") {
+            Some(error)
+        } else if room.started {
+            Some(RoomError::new(ErrorCode::InvalidSettings, "The game has already started"))
+        } else if !settings.is_valid() {
+            Some(RoomError::new(ErrorCode::InvalidSettings, "The settings are out of range"))
+        } else if settings.mode != GameMode::Race && room.players.len() > 1 {
+            Some(RoomError::new(ErrorCode::InvalidSettings, "Only races can have more than one player"))
+        } else if settings.max_players < room.players.len() {
+            Some(RoomError::new(ErrorCode::InvalidSettings, format!("There are already {} players", room.players.len())))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return self.send_packet(client_id, error.into());
+        }
+        let teams_changed = room.settings.teams != settings.teams;
+        room.settings = settings;
+        if teams_changed {
+            room.assign_teams();
+        }
+
+        self.send_update_players(true, true);
+    }
+
+    fn choose_team(&mut self, client_id: u32, team: u32) {
+        if let Err(error) = self.room.choose_team(client_id, team) {
+            return self.send_packet(client_id, error.into());
+        }
+
+        self.send_update_players(true, true);
+    }
+
+    fn add_bot(&mut self, client_id: u32, bot: Client, difficulty: BotDifficulty) {
+        let room = &mut self.room;
+        let error = if let Err(error) = room.check_host(client_id, "add bots") {
+            Some(error)
+        } else if room.started {
+            Some(RoomError::new(ErrorCode::AlreadyStarted, "The game has already started"))
+        } else if room.is_full() {
+            Some(RoomError::new(ErrorCode::RoomFull, "The room is full"))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return self.send_packet(client_id, error.into());
+        }
+
+        let name = (1..)
+            .map(|n| format!("Bot {n}"))
+            .find(|name| room.players.iter().all(|player| &player.name != name))
+            .unwrap();
+        let mut player = Player::new(bot.id, name);
+        player.bot = Some(difficulty);
+        room.add_player(player);
+        self.clients.insert(bot.id, bot);
+
+        self.send_update_players(true, true);
+    }
+
+    fn kick_player(&mut self, client_id: u32, player_index: u32) {
+        let kicked = match self.room.kick_player(client_id, player_index) {
+            Ok(kicked) => kicked,
+            Err(error) => return self.send_packet(client_id, error.into()),
+        };
+
+        if let Some(kicked_client_id) = kicked.client_id {
+            if kicked.bot.is_some() {
+                // Dropping the client stops the bot
+                self.clients.remove(&kicked_client_id);
+            } else {
+                // By address, so that reconnecting doesn't get around it
+                if let Some(ip) = self.clients.get(&kicked_client_id).and_then(|client| client.ip) {
+                    self.room.kicked.push(ip);
+                }
+                self.send_packet(kicked_client_id, ServerMessage::Kicked {});
+                self.add_viewer(kicked_client_id);
+            }
+        }
+
+        if self.room.started {
+            self.send_update_game_all();
+        }
+        self.send_update_players(true, true);
+    }
+
+    fn transfer_host(&mut self, client_id: u32, player_index: u32) {
+        if let Err(error) = self.room.transfer_host(client_id, player_index) {
+            return self.send_packet(client_id, error.into());
+        }
+
+        self.send_update_players(true, true);
+    }
+
+    fn lock_lobby(&mut self, client_id: u32, locked: bool) {
+        if let Err(error) = self.room.check_host(client_id, "lock the lobby") {
+            return self.send_packet(client_id, error.into());
+        }
+        self.room.locked = locked;
+
+        self.send_update_players(true, true);
+    }
+
+    async fn rematch(&mut self, client_id: u32) {
+        let ready = match self.room.vote_rematch(client_id) {
+            Ok(ready) => ready,
+            Err(error) => return self.send_packet(client_id, error.into()),
+        };
+
+        if ready {
+            // Keep the replay of the last game before starting the next one
+            self.save_replay().await;
+            self.room.rematch();
+            println!("Rematch in room {}", self.room.id);
+            self.send_update_game_all();
+        }
+        self.send_update_players(true, true);
+    }
+
+    fn start_game(&mut self, client_id: u32) {
+        let error = if let Err(error) = self.room.check_host(client_id, "start the game") {
+            Some(error)
+        } else if self.room.started {
+            Some(RoomError::new(ErrorCode::AlreadyStarted, "The game has already started"))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return self.send_packet(client_id, error.into());
+        }
+        self.room.start();
+
+        self.send_update_game_all();
+        self.send_update_players(false, true);
+    }
+
+    fn pick_cards(&mut self, client_id: u32, card_indexes: &[usize]) {
+        if let Err(error) = self.room.pick_cards(client_id, card_indexes) {
+            println!("[{client_id}] Pick rejected: {}", error.message);
+            return self.send_packet(client_id, error.into());
+        }
+
+        self.send_update_game_all();
+    }
+
+    fn request_hint(&mut self, client_id: u32) {
+        if let Err(error) = self.room.request_hint(client_id) {
+            println!("[{client_id}] Hint rejected: {}", error.message);
+            return self.send_packet(client_id, error.into());
+        }
+
+        self.send_update_game_all();
+    }
+
+    fn send_practice_summary(&self) {
+        for (player_index, player) in self.room.players.iter().enumerate() {
+            if let Some(client_id) = player.client_id {
+                let packet = self.room.get_practice_summary_packet(player_index as u32);
+                self.send_packet(client_id, packet);
+            }
+        }
+    }
+
+    /// Send each player an update that includes their own state
+    fn send_update_game_all(&self) {
+        for (player_index, player) in self.room.players.iter().enumerate() {
+            if let Some(client_id) = player.client_id {
+                let data = self.room.get_update_game_packet(Some(player_index as u32));
+                self.send_packet(client_id, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::client::ClientReceiver;
+    use crate::clock::ManualClock;
+    use crate::puzzle::PuzzleResults;
+    use crate::replay::ReplayStore;
+
+    /// A room task that isn't running, so its handlers can be called directly
+    fn new_task() -> RoomTask {
+        let clock = Arc::new(ManualClock::new());
+        let state = Arc::new(Mutex::new(ServerState {
+            next_client_id: 0,
+            rooms: HashMap::new(),
+            room_ips: HashMap::new(),
+            clock: clock.clone(),
+            replays: ReplayStore::new(None),
+            puzzle_results: PuzzleResults::default(),
+            room_summaries: HashMap::new(),
+            room_list_subscribers: HashMap::new(),
+        }));
+        RoomTask {
+            room: Room::new("test".to_string(), clock),
+            clients: HashMap::new(),
+            state,
+            summary: None,
+        }
+    }
+
+    fn view(task: &mut RoomTask, client_id: u32) -> ClientReceiver {
+        view_from(task, client_id, None)
+    }
+
+    fn view_from(task: &mut RoomTask, client_id: u32, ip: Option<IpAddr>) -> ClientReceiver {
+        let (mut client, receiver) = Client::new(client_id);
+        client.ip = ip;
+        task.view_room(client, None, None);
+        receiver
+    }
+
+    fn is_rejected(packets: &[ServerMessage], expected: RejectReason) -> bool {
+        packets.iter().any(|packet| matches!(packet, ServerMessage::RejectJoinGame { reason, .. } if *reason == expected))
+    }
+
+    /// Every packet that has been sent so far
+    async fn packets(receiver: &mut ClientReceiver) -> Vec<ServerMessage> {
+        let mut packets = Vec::new();
+        while let Ok(Some(packet)) = timeout(Duration::ZERO, receiver.recv()).await {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn a_client_only_gets_one_seat() {
+        let mut task = new_task();
+        let mut receiver = view(&mut task, 1);
+        for n in 0..10 {
+            task.join_room(1, format!("a{n}"), None);
+        }
+        assert_eq!(task.room.players.len(), 1);
+        let rejected = packets(&mut receiver).await.into_iter()
+            .filter(|packet| matches!(packet, ServerMessage::RejectJoinGame { reason: RejectReason::AlreadyJoined, .. }))
+            .count();
+        assert_eq!(rejected, 9);
+
+        task.disconnect(1);
+        assert!(task.room.players.is_empty());
+        assert!(task.room.delete_time.is_some());
+    }
+
+    #[tokio::test]
+    async fn kicked_addresses_cant_join_again() {
+        let mut task = new_task();
+        let ip = |n| Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)));
+        view_from(&mut task, 1, ip(1));
+        task.join_room(1, "host".to_string(), None);
+        view_from(&mut task, 2, ip(2));
+        task.join_room(2, "b".to_string(), None);
+
+        task.kick_player(1, 1);
+        assert_eq!(task.room.players.len(), 1);
+        assert!(task.room.viewers.contains(&2));
+
+        // Reconnecting gives a new client id, but the same address
+        let mut receiver = view_from(&mut task, 3, ip(2));
+        task.join_room(3, "c".to_string(), None);
+        assert!(is_rejected(&packets(&mut receiver).await, RejectReason::Kicked));
+        assert_eq!(task.room.players.len(), 1);
+
+        view_from(&mut task, 4, ip(4));
+        task.join_room(4, "d".to_string(), None);
+        assert_eq!(task.room.players.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_token_takes_back_a_seat() {
+        let mut task = new_task();
+        view(&mut task, 1);
+        task.join_room(1, "a".to_string(), None);
+        view(&mut task, 2);
+        task.join_room(2, "b".to_string(), None);
+        task.room.start();
+        task.room.players[0].score = 3;
+        task.room.players[1].score = 5;
+        let token_a = task.room.players[0].token.clone();
+        let token_b = task.room.players[1].token.clone();
+
+        // Without the token, the seats can't be taken
+        let mut receiver = view(&mut task, 3);
+        task.join_room(3, "c".to_string(), Some("wrong".to_string()));
+        task.join_room(3, "c".to_string(), None);
+        task.join_room(3, "a".to_string(), None);
+        let rejected = packets(&mut receiver).await.into_iter()
+            .filter(|packet| matches!(packet, ServerMessage::RejectJoinGame { reason: RejectReason::Started, .. }))
+            .count();
+        assert_eq!(rejected, 3);
+        assert_eq!(task.room.players[0].client_id, Some(1));
+
+        // The old connection is still open, so it is left watching
+        let mut receiver = view(&mut task, 4);
+        task.join_room(4, "a".to_string(), Some(token_a));
+        let packets_4 = packets(&mut receiver).await;
+        assert!(packets_4.iter().any(|packet| matches!(packet, ServerMessage::Joined { player_index: 0, .. })));
+        assert_eq!(task.room.players[0].client_id, Some(4));
+        assert_eq!(task.room.players[0].score, 3);
+        assert!(task.room.viewers.contains(&1));
+        assert!(!task.room.viewers.contains(&4));
+
+        // After disconnecting
+        task.disconnect(2);
+        assert_eq!(task.room.players[1].client_id, None);
+        view(&mut task, 5);
+        task.join_room(5, "b".to_string(), Some(token_b));
+        assert_eq!(task.room.players.len(), 2);
+        assert_eq!(task.room.players[1].client_id, Some(5));
+        assert_eq!(task.room.players[1].score, 5);
+    }
+}
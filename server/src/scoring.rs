@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Time;
+
+/// How players get points, and when the game ends
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ScoringRule {
+    /// 1 point for a set, and -1 for a wrong pick
+    #[default]
+    Classic,
+    /// Up to `max_bonus` extra points for finding a set soon after the board
+    /// changed. The bonus runs out after `bonus_time` (ms).
+    SpeedBonus { max_bonus: i32, bonus_time: i32 },
+    /// Each set found in a row is worth a point more than the last, up to
+    /// `max_multiplier`. A wrong pick starts again from 1.
+    Streak { max_multiplier: i32 },
+    /// Classic points, and the game ends when somebody has `target` points
+    FirstTo { target: i32 },
+}
+
+impl ScoringRule {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            ScoringRule::Classic => true,
+            ScoringRule::SpeedBonus { max_bonus, bonus_time } => {
+                (0..=10).contains(&max_bonus) && (1..=60_000).contains(&bonus_time)
+            }
+            ScoringRule::Streak { max_multiplier } => (1..=10).contains(&max_multiplier),
+            ScoringRule::FirstTo { target } => (1..=100).contains(&target),
+        }
+    }
+
+    /// Points for finding a set. `streak` counts this set, and `board_age` is
+    /// the time since the board last changed.
+    pub fn set_points(&self, streak: u32, board_age: Time) -> i32 {
+        match *self {
+            ScoringRule::Classic | ScoringRule::FirstTo { .. } => 1,
+            ScoringRule::SpeedBonus { max_bonus, bonus_time } => {
+                let time_left = (Time::from(bonus_time) - board_age).max(0);
+                1 + (Time::from(max_bonus) * time_left / Time::from(bonus_time)) as i32
+            }
+            ScoringRule::Streak { max_multiplier } => (streak as i32).clamp(1, max_multiplier),
+        }
+    }
+
+    /// Points for a wrong pick
+    pub fn wrong_points(&self) -> i32 {
+        -1
+    }
+
+    /// Whether a player with these points has won, which ends the game
+    pub fn is_won(&self, points: i32) -> bool {
+        match *self {
+            ScoringRule::FirstTo { target } => points >= target,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_bonus_runs_out_over_bonus_time() {
+        let rule = ScoringRule::SpeedBonus { max_bonus: 4, bonus_time: 10_000 };
+        assert_eq!(rule.set_points(1, 0), 5);
+        assert_eq!(rule.set_points(1, 5000), 3);
+        assert_eq!(rule.set_points(1, 9999), 1);
+        assert_eq!(rule.set_points(1, 10_000), 1);
+        assert_eq!(rule.set_points(1, 60_000), 1);
+    }
+
+    #[test]
+    fn streak_is_capped_at_max_multiplier() {
+        let rule = ScoringRule::Streak { max_multiplier: 3 };
+        let points: Vec<_> = (0..6).map(|streak| rule.set_points(streak, 0)).collect();
+        assert_eq!(points, [1, 1, 2, 3, 3, 3]);
+        assert_eq!(rule.wrong_points(), -1);
+    }
+
+    #[test]
+    fn first_to_ends_at_the_target() {
+        let rule = ScoringRule::FirstTo { target: 5 };
+        assert_eq!(rule.set_points(3, 0), 1);
+        assert!(!rule.is_won(4));
+        assert!(rule.is_won(5));
+        assert!(rule.is_won(6));
+        assert!(!ScoringRule::Classic.is_won(100));
+    }
+}
@@ -1,166 +1,101 @@
-use futures_util::SinkExt;
 use std::collections::HashMap;
-use tokio_tungstenite::tungstenite::Message;
-
-use crate::messages::ServerMessage;
-use crate::room::{Client, Player, Room};
-
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::client::Client;
+use crate::clock::Clock;
+use crate::messages::{RejectReason, RoomSummary, ServerMessage};
+use crate::puzzle::PuzzleResults;
+use crate::replay::ReplayStore;
+use crate::room::RoomAccess;
+use crate::room_task::RoomHandle;
+use crate::util;
+
+/// Rooms that one address can have open at once
+const MAX_ROOMS_PER_IP: usize = 10;
+
+/// Keeps track of the running rooms. Each room runs in its own task, so this
+/// is only locked to look up rooms, and never while sending to clients.
 #[derive(Debug)]
 pub struct ServerState {
-    pub clients: HashMap<u32, Client>,
     pub next_client_id: u32,
-    pub rooms: HashMap<String, Room>,
+    pub rooms: HashMap<String, RoomHandle>,
+    /// The address that made each room
+    pub room_ips: HashMap<String, IpAddr>,
+    /// Shared by all rooms
+    pub clock: Arc<dyn Clock>,
+    pub replays: ReplayStore,
+    pub puzzle_results: PuzzleResults,
+    /// Public rooms, as each room last reported itself
+    pub room_summaries: HashMap<String, RoomSummary>,
+    /// Clients that are sent the room list when it changes
+    pub room_list_subscribers: HashMap<u32, Client>,
 }
 
 impl ServerState {
-    pub async fn send_packet(&mut self, client_id: u32, data: ServerMessage) {
-        let text = serde_json::to_string(&data).unwrap();
-        let client = self.clients.get_mut(&client_id).unwrap();
-        _ = client.sender.send(Message::Text(text.into())).await;
+    pub fn new_client_id(&mut self) -> u32 {
+        self.next_client_id += 1;
+        self.next_client_id - 1
     }
 
-    pub async fn view_room(&mut self, client_id: u32, room_id: &str) {
-        if !self.rooms.contains_key(room_id) {
-            self.rooms.insert(room_id.to_string(), Room::new(room_id.to_string()));
+    /// Get the room with the given id, creating it if it doesn't exist. New
+    /// rooms remove themselves from `state` when they are deleted.
+    ///
+    /// Rooms made this way are public, as the client lets players meet in a
+    /// room by typing the same name. Private rooms are only made by
+    /// `create_room`, with ids that can't be guessed, so guessing an id can
+    /// only ever make a new public room.
+    pub fn get_room(&mut self, room_id: &str, ip: IpAddr, state: &Arc<Mutex<ServerState>>) -> Result<RoomHandle, RejectReason> {
+        if let Some(room) = self.rooms.get(room_id) {
+            return Ok(room.clone());
         }
 
-        let room = self.rooms.get_mut(room_id).unwrap();
-        room.viewers.push(client_id);
-        self.clients.get_mut(&client_id).unwrap().room_id = Some(room.id.clone());
-
-        let packet = ServerMessage::UpdatePlayers { players: room.get_player_updates(), started: room.started };
-        self.send_packet(client_id, packet).await;
+        self.check_room_limit(ip)?;
+        let room = RoomHandle::spawn(room_id.to_string(), RoomAccess::Public, self.clock.clone(), state.clone());
+        self.rooms.insert(room_id.to_string(), room.clone());
+        self.room_ips.insert(room_id.to_string(), ip);
+        Ok(room)
     }
 
-    pub async fn join_room(&mut self, client_id: u32, client_name: String) {
-        let client = self.clients.get_mut(&client_id).unwrap();
-        client.name = Some(client_name);
-        let room = self.rooms.get_mut(client.room_id.as_ref().unwrap()).unwrap();
-        let mut success = false;
-        if room.started {
-            for player in room.players.iter_mut() {
-                if player.client_id.is_none() && Some(&player.name) == client.name.as_ref() {
-                    player.client_id = Some(client.id);
-                    success = true;
-                }
-            }
-        } else {
-            success = true;
-            for player in room.players.iter() {
-                if Some(&player.name) == client.name.as_ref() {
-                    success = false;
-                }
-            }
-
-            if success {
-                room.viewers = room.viewers
-                    .iter()
-                    .map(|&x| x)
-                    .filter(|&id| id != client.id)
-                    .collect();
-                room.add_player(Player { client_id: Some(client.id), name: client.name.clone().unwrap(), score: 0, minus_score: 0, timeout: 0 });
-            }
-        }
-
-        if success {
-            client.room_id = Some(room.id.to_string());
-            if room.started {
-                let player_index = room.get_player_index(client.id);
-                let packet = room.get_update_game_packet(player_index);
-                self.send_packet(client_id, packet).await;
-            }
-
-            let client = self.clients.get_mut(&client_id).unwrap();
-            let room_id = client.room_id.clone().unwrap();
-            self.send_update_players(&room_id, true, true).await;
-        } else {
-            self.send_packet(client_id, ServerMessage::RejectJoinGame {}).await;
+    fn check_room_limit(&self, ip: IpAddr) -> Result<(), RejectReason> {
+        let count = self.room_ips.values().filter(|&&room_ip| room_ip == ip).count();
+        if count >= MAX_ROOMS_PER_IP {
+            println!("{ip} already has {count} rooms open");
+            return Err(RejectReason::TooManyRooms);
         }
+        Ok(())
     }
 
-    pub async fn leave_view_room(&mut self, client_id: u32) {
-        let client = self.clients.get(&client_id).unwrap();
-
-        if let Some(room_id) = client.room_id.clone() {
-            self.leave_room(client_id);
-            self.view_room(client_id, &room_id).await;
-
-            let room = self.rooms.get_mut(&room_id).unwrap();
-            room.check_empty();
-            self.send_update_players(&room_id, true, true).await;
-        }
+    pub fn remove_room(&mut self, room_id: &str) {
+        self.rooms.remove(room_id);
+        self.room_ips.remove(room_id);
     }
 
-    pub async fn disconnect(&mut self, client_id: u32) {
-        let client = self.clients.get(&client_id).unwrap();
-
-        if let Some(room_id) = client.room_id.clone() {
-            self.leave_room(client_id);
-
-            let room = self.rooms.get_mut(&room_id).unwrap();
-            room.check_empty();
-            self.send_update_players(&room_id, true, true).await;
-        }
+    pub fn get_room_list_packet(&self) -> ServerMessage {
+        let mut rooms: Vec<_> = self.room_summaries.values().cloned().collect();
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        ServerMessage::RoomList { rooms }
     }
 
-    pub fn leave_room(&mut self, client_id: u32) {
-        let client = self.clients.get_mut(&client_id).unwrap();
-        if let Some(room) = self.rooms.get_mut(client.room_id.as_ref().unwrap()) {
-            client.room_id = None;
-            room.remove_client(client.id);
-        }
-    }
-
-    pub async fn send_update_players(&mut self, room_id: &str, send_to_players: bool, send_to_viewers: bool) {
-        let room = &self.rooms[room_id];
-        let packet = ServerMessage::UpdatePlayers {
-            players: room.get_player_updates(),
-            started: room.started,
+    /// Update the room list, and get the clients to send the new list to.
+    /// They should be sent to after unlocking.
+    pub fn set_room_summary(&mut self, room_id: &str, summary: Option<RoomSummary>) -> (ServerMessage, Vec<Client>) {
+        match summary {
+            Some(summary) => self.room_summaries.insert(room_id.to_string(), summary),
+            None => self.room_summaries.remove(room_id),
         };
-        if send_to_players {
-            for player in room.players.to_vec() {
-                if let Some(client_id) = player.client_id {
-                    self.send_packet(client_id, packet.clone()).await;
-                }
-            }
-        }
-        if send_to_viewers {
-            let room = &self.rooms[room_id];
-            for client_id in room.viewers.to_vec() {
-                self.send_packet(client_id, packet.clone()).await;
-            }
-        }
-    }
-
-    pub async fn start_game(&mut self, client_id: u32) {
-        let client = self.clients.get(&client_id).unwrap();
-        let room_id = client.room_id.clone().unwrap();
-        let room = self.rooms.get_mut(&room_id).unwrap();
-        room.start();
-
-        self.send_update_game_all(&room_id).await;
-        self.send_update_players(&room_id, false, true).await;
+        let subscribers = self.room_list_subscribers.values().cloned().collect();
+        (self.get_room_list_packet(), subscribers)
     }
 
-    pub async fn pick_cards(&mut self, client_id: u32, card_indexes: &[usize]) {
-        let client = self.clients.get(&client_id).unwrap();
-        let room_id = client.room_id.clone().unwrap();
-        let room = self.rooms.get_mut(&room_id).unwrap();
-        room.pick_cards(client_id, card_indexes);
-
-        self.send_update_game_all(&room_id).await;
-    }
-
-    async fn send_update_game_all(&mut self, room_id: &str) {
-        let room = &self.rooms[room_id];
-        let data = room.get_update_game_packet(None);
-
-        let client_ids: Vec<_> = room.players.iter()
-            .filter_map(|player| player.client_id)
-            .collect();
-
-        for client_id in client_ids {
-            self.send_packet(client_id, data.clone()).await;
-        }
+    /// Create a room with a new id that can't be guessed, and get the id
+    pub fn create_room(&mut self, access: RoomAccess, ip: IpAddr, state: &Arc<Mutex<ServerState>>) -> Result<String, RejectReason> {
+        self.check_room_limit(ip)?;
+        let room_id = util::new_token();
+        let room = RoomHandle::spawn(room_id.clone(), access, self.clock.clone(), state.clone());
+        self.rooms.insert(room_id.clone(), room);
+        self.room_ips.insert(room_id.clone(), ip);
+        Ok(room_id)
     }
 }
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+
+use crate::card::DeckSpec;
+use crate::scoring::ScoringRule;
+
+/// Most players that a room can be set to allow, including bots
+pub const MAX_PLAYERS: usize = 16;
+/// Most spectators that a room can be set to allow
+pub const MAX_SPECTATORS: usize = 100;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeckVariant {
+    /// 27 cards, without fill
+    Junior,
+    /// 81 cards
+    Classic,
+    /// 243 cards, with an extra attribute
+    Expert,
+}
+
+impl DeckVariant {
+    pub fn spec(&self) -> DeckSpec {
+        match self {
+            DeckVariant::Junior => DeckSpec::JUNIOR,
+            DeckVariant::Classic => DeckSpec::CLASSIC,
+            DeckVariant::Expert => DeckSpec::EXPERT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    /// Players race each other to find sets
+    #[default]
+    Race,
+    /// One player clears the deck, and is sent their times at the end
+    Practice,
+    /// One player finds every set on the board of the day, without refills
+    Puzzle,
+}
+
+/// Settings that can be changed in the lobby, before the game starts
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RoomSettings {
+    /// How long a player has to wait after a wrong pick (ms)
+    pub penalty_timeout: i32,
+    /// How long picked cards stay highlighted (ms)
+    pub highlight_expire: i32,
+    /// Number of cards on the board
+    pub board_size: usize,
+    /// Time between starting the game and the cards being shown (ms)
+    pub countdown: i32,
+    pub deck: DeckVariant,
+    /// Deals the same cards every time. Random if not given.
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// Score lost for each card revealed by a hint. Hints are disabled if null.
+    #[serde(default = "default_hint_cost")]
+    pub hint_cost: Option<i32>,
+    #[serde(default)]
+    pub mode: GameMode,
+    #[serde(default)]
+    pub scoring: ScoringRule,
+    /// Number of teams that players are split into. 0 means everybody plays alone.
+    #[serde(default)]
+    pub teams: u32,
+    /// A wrong pick times out the player's whole team
+    #[serde(default)]
+    pub team_penalty: bool,
+    /// Including bots
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+    /// Clients viewing the room without playing
+    #[serde(default = "default_max_spectators")]
+    pub max_spectators: usize,
+}
+
+fn default_max_players() -> usize {
+    8
+}
+
+fn default_max_spectators() -> usize {
+    50
+}
+
+fn default_hint_cost() -> Option<i32> {
+    Some(1)
+}
+
+impl RoomSettings {
+    pub fn is_valid(&self) -> bool {
+        (0..=60_000).contains(&self.penalty_timeout)
+            && (0..=60_000).contains(&self.highlight_expire)
+            && (0..=10_000).contains(&self.countdown)
+            && (3..=21).contains(&self.board_size)
+            && self.board_size as i32 <= self.deck.spec().card_count()
+            && self.hint_cost.is_none_or(|cost| (0..=10).contains(&cost))
+            && self.scoring.is_valid()
+            && (self.teams == 0 || (2..=4).contains(&self.teams))
+            && (1..=MAX_PLAYERS).contains(&self.max_players)
+            && self.max_spectators <= MAX_SPECTATORS
+    }
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            penalty_timeout: 10000,
+            highlight_expire: 5000,
+            board_size: 12,
+            countdown: 3000,
+            deck: DeckVariant::Classic,
+            seed: None,
+            hint_cost: default_hint_cost(),
+            mode: GameMode::Race,
+            scoring: ScoringRule::Classic,
+            teams: 0,
+            team_penalty: false,
+            max_players: default_max_players(),
+            max_spectators: default_max_spectators(),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::card::{Card, DeckSpec};
+
+/// Indexes of 3 cards on the board, in increasing order
+pub type Set = [usize; 3];
+
+/// Call `found` for every set on the board, until it returns false
+fn for_each_set(deck: &DeckSpec, cards: &[Option<Card>], mut found: impl FnMut(Set) -> bool) {
+    let indexes: HashMap<i32, usize> = cards
+        .iter()
+        .enumerate()
+        .filter_map(|(i, card)| card.as_ref().map(|card| (card.id, i)))
+        .collect();
+
+    for (i, card_i) in cards.iter().enumerate() {
+        let Some(card_i) = card_i else { continue };
+        for (j, card_j) in cards.iter().enumerate().skip(i + 1) {
+            let Some(card_j) = card_j else { continue };
+            for id in deck.get_third_cards(card_i, card_j) {
+                if let Some(&k) = indexes.get(&id) {
+                    if k > j && !found([i, j, k]) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Get if the board has at least one set
+pub fn has_set(deck: &DeckSpec, cards: &[Option<Card>]) -> bool {
+    let mut result = false;
+    for_each_set(deck, cards, |_| {
+        result = true;
+        false
+    });
+    result
+}
+
+/// Get every set on the board
+pub fn find_sets(deck: &DeckSpec, cards: &[Option<Card>]) -> Vec<Set> {
+    let mut sets = Vec::new();
+    for_each_set(deck, cards, |set| {
+        sets.push(set);
+        true
+    });
+    sets
+}
+
+pub fn count_sets(deck: &DeckSpec, cards: &[Option<Card>]) -> usize {
+    find_sets(deck, cards).len()
+}
+
+/// Get the largest number of sets on the board that don't share any cards
+pub fn max_disjoint_sets(deck: &DeckSpec, cards: &[Option<Card>]) -> Vec<Set> {
+    let sets = find_sets(deck, cards);
+    let mut search = DisjointSearch {
+        sets_by_first_card: vec![Vec::new(); cards.len()],
+        // Empty spaces can't be used
+        used: cards.iter().map(|card| card.is_none()).collect(),
+        current: Vec::new(),
+        best: Vec::new(),
+    };
+    for set in sets {
+        search.sets_by_first_card[set[0]].push(set);
+    }
+
+    let card_count = cards.iter().flatten().count();
+    search.search(0, card_count);
+    search.best
+}
+
+struct DisjointSearch {
+    sets_by_first_card: Vec<Vec<Set>>,
+    used: Vec<bool>,
+    current: Vec<Set>,
+    best: Vec<Set>,
+}
+
+impl DisjointSearch {
+    /// Try every way of using the cards from `start`. Each card is either
+    /// left out, or is the first card of one of the chosen sets.
+    fn search(&mut self, start: usize, cards_left: usize) {
+        if self.current.len() + cards_left / 3 <= self.best.len() {
+            return;
+        }
+
+        let Some(first) = (start..self.used.len()).find(|&i| !self.used[i]) else {
+            self.best = self.current.clone();
+            return;
+        };
+
+        for set_index in 0..self.sets_by_first_card[first].len() {
+            let set = self.sets_by_first_card[first][set_index];
+            if set.iter().any(|&i| self.used[i]) {
+                continue;
+            }
+
+            for &i in set.iter() {
+                self.used[i] = true;
+            }
+            self.current.push(set);
+            self.search(first + 1, cards_left.saturating_sub(3));
+            self.current.pop();
+            for &i in set.iter() {
+                self.used[i] = false;
+            }
+        }
+
+        self.search(first + 1, cards_left.saturating_sub(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::seq::index;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// Each card is the value of each attribute, like "0120". Empty strings are empty spaces.
+    fn board(deck: &DeckSpec, cards: &[&str]) -> Vec<Option<Card>> {
+        cards
+            .iter()
+            .map(|card| {
+                let id = card.chars().rev().fold(None, |id, c| {
+                    Some(id.unwrap_or(0) * deck.values() as i32 + c.to_digit(10).unwrap() as i32)
+                });
+                id.map(|id| deck.id_to_card(id))
+            })
+            .collect()
+    }
+
+    /// Every set, by checking every 3 cards
+    fn find_sets_slowly(deck: &DeckSpec, cards: &[Option<Card>]) -> Vec<Set> {
+        let mut sets = Vec::new();
+        for i in 0..cards.len() {
+            for j in i + 1..cards.len() {
+                for k in j + 1..cards.len() {
+                    if deck.check_match(&cards[i], &cards[j], &cards[k]) {
+                        sets.push([i, j, k]);
+                    }
+                }
+            }
+        }
+        sets
+    }
+
+    /// Most sets that don't share cards, by trying every way to use the cards
+    /// that aren't `used` yet
+    fn count_disjoint_slowly(sets: &[Set], used: &mut Vec<bool>) -> usize {
+        let Some(first) = used.iter().position(|&used| !used) else { return 0 };
+        used[first] = true;
+        let mut best = count_disjoint_slowly(sets, used);
+        for set in sets.iter().filter(|set| set.contains(&first)) {
+            if set.iter().all(|&i| i == first || !used[i]) {
+                set.iter().for_each(|&i| used[i] = true);
+                best = best.max(1 + count_disjoint_slowly(sets, used));
+                set.iter().filter(|&&i| i != first).for_each(|&i| used[i] = false);
+            }
+        }
+        used[first] = false;
+        best
+    }
+
+    #[test]
+    fn finds_sets_on_a_classic_board() {
+        let deck = DeckSpec::CLASSIC;
+        let cards = board(&deck, &["0000", "1111", "2222", "0001", "0002", "", "1101", "1121"]);
+        assert!(has_set(&deck, &cards));
+        assert_eq!(find_sets(&deck, &cards), vec![[0, 1, 2], [0, 3, 4], [1, 6, 7]]);
+        assert_eq!(count_sets(&deck, &cards), 3);
+        // Taking [0, 1, 2] first would leave no other set
+        assert_eq!(max_disjoint_sets(&deck, &cards), vec![[0, 3, 4], [1, 6, 7]]);
+    }
+
+    #[test]
+    fn finds_sets_with_more_values() {
+        // Any 3 of the 4 values make a set
+        let deck = DeckSpec::new(2, 4).unwrap();
+        let cards = board(&deck, &["00", "11", "22", "33"]);
+        assert_eq!(find_sets(&deck, &cards), vec![[0, 1, 2], [0, 1, 3], [0, 2, 3], [1, 2, 3]]);
+        assert_eq!(max_disjoint_sets(&deck, &cards).len(), 1);
+
+        let cards = board(&deck, &["00", "10", "01", ""]);
+        assert!(!has_set(&deck, &cards));
+        assert_eq!(count_sets(&deck, &cards), 0);
+        assert!(max_disjoint_sets(&deck, &cards).is_empty());
+    }
+
+    #[test]
+    fn finds_the_same_sets_as_checking_every_3_cards() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for deck in [DeckSpec::CLASSIC, DeckSpec::EXPERT, DeckSpec::new(3, 4).unwrap()] {
+            for _ in 0..100 {
+                let ids = index::sample(&mut rng, deck.card_count() as usize, 12);
+                let cards: Vec<_> = ids.iter().map(|id| Some(deck.id_to_card(id as i32))).collect();
+                let mut sets = find_sets(&deck, &cards);
+                // With more than 3 values, a pair can have several third cards in any order
+                sets.sort();
+                assert_eq!(sets, find_sets_slowly(&deck, &cards));
+
+                let disjoint = max_disjoint_sets(&deck, &cards);
+                assert!(disjoint.iter().all(|set| sets.contains(set)));
+                let mut used: Vec<_> = disjoint.iter().flatten().collect();
+                used.sort();
+                used.dedup();
+                assert_eq!(used.len(), disjoint.len() * 3);
+                assert_eq!(disjoint.len(), count_disjoint_slowly(&sets, &mut vec![false; cards.len()]));
+            }
+        }
+    }
+}
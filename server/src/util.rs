@@ -1,6 +1,28 @@
+use rand::Rng;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Time since unix epoch (ms)
-pub fn get_now() -> i32 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i32
+/// A random string that can't be guessed
+pub fn new_token() -> String {
+    format!("{:032x}", rand::rng().random::<u128>())
+}
+
+/// Days since 1970-01-01, in UTC
+pub fn today() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() / (24 * 60 * 60)) as i64
+}
+
+/// The date of a day from `today`, like 2024-03-09
+pub fn format_day(day: i64) -> String {
+    // From http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = day + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day_of_month:02}")
 }